@@ -0,0 +1,679 @@
+//! Typed functions for talking to a launch server, independent of the command line interface.
+
+use crate::shared::{
+    Bundle, BundleConfig, Deployment, HistoryEntry, ListEntry, LogEntry, ManifestEntry,
+    VersionInfo, ARCHIVE_ENCODING_HEADER, CONFIG_ENTRY, FORCE_HEADER, IDEMPOTENCY_HEADER, NDJSON,
+    OVERWRITE_HEADER, PREPARE_HEADER, TTL_HEADER, VERBOSE_HEADER,
+};
+use flate2::{write::GzEncoder, Compression};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+use ulid::Ulid;
+use walkdir::WalkDir;
+
+/// Archives larger than this are uploaded in chunks of this size
+pub const CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+/// How often a single chunk is retried before the upload is aborted
+const CHUNK_ATTEMPTS: usize = 5;
+
+/// How long a request may stall while connecting, sending or waiting for the next bytes.
+/// Uploads are answered once the bundle is deployed, so this has to cover that as well.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+
+static AGENT: OnceLock<ureq::Agent> = OnceLock::new();
+
+/// Overrides [`DEFAULT_TIMEOUT`] for all requests, only effective before the first one is made
+pub fn set_timeout(timeout: Duration) {
+    AGENT.set(build_agent(timeout)).ok();
+}
+
+fn agent() -> &'static ureq::Agent {
+    AGENT.get_or_init(|| build_agent(DEFAULT_TIMEOUT))
+}
+
+/// Timeouts apply per socket operation, so large uploads are fine as long as they progress
+fn build_agent(timeout: Duration) -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(timeout)
+        .timeout_read(timeout)
+        .timeout_write(timeout)
+        .build()
+}
+
+/// Failure of an operation against a launch server, split by what went wrong
+#[derive(Debug)]
+pub enum ClientError {
+    /// The launch config is missing or invalid
+    Config(String),
+    /// The bundle archive could not be created, read or written
+    Archive {
+        action: &'static str,
+        source: io::Error,
+    },
+    /// The server could not be reached
+    Network {
+        action: &'static str,
+        source: Box<ureq::Transport>,
+    },
+    /// The server answered with an error status
+    Status {
+        action: &'static str,
+        code: u16,
+        body: String,
+    },
+    /// The server answered with something other than the expected response
+    Response {
+        action: &'static str,
+        source: io::Error,
+    },
+}
+
+pub type Result<T, E = ClientError> = std::result::Result<T, E>;
+
+/// Attaches the action which failed to I/O and request errors, mirroring `anyhow::Context`
+trait Context<T> {
+    fn context(self, action: &'static str) -> Result<T>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn context(self, action: &'static str) -> Result<T> {
+        self.map_err(|source| ClientError::Archive { action, source })
+    }
+}
+
+impl<T> Context<T> for std::result::Result<T, ureq::Error> {
+    fn context(self, action: &'static str) -> Result<T> {
+        self.map_err(|error| match error {
+            ureq::Error::Status(code, response) => ClientError::Status {
+                action,
+                code,
+                body: response.into_string().unwrap_or_default(),
+            },
+            ureq::Error::Transport(transport) => ClientError::Network {
+                action,
+                source: Box::new(transport),
+            },
+        })
+    }
+}
+
+/// Parses the body of a successful response
+fn parse<T: serde::de::DeserializeOwned>(response: ureq::Response) -> Result<T> {
+    response
+        .into_json()
+        .map_err(|source| ClientError::Response {
+            action: "failed to deserialize response",
+            source,
+        })
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Config(message) => f.write_str(message),
+            ClientError::Archive { action, .. }
+            | ClientError::Network { action, .. }
+            | ClientError::Response { action, .. } => f.write_str(action),
+            ClientError::Status { action, code, .. } => write!(f, "{action}: status code {code}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Archive { source, .. } | ClientError::Response { source, .. } => {
+                Some(source)
+            }
+            ClientError::Network { source, .. } => Some(source.as_ref()),
+            ClientError::Config(_) | ClientError::Status { .. } => None,
+        }
+    }
+}
+
+/// Optional behaviour attached to an upload
+#[derive(Debug, Clone, Default)]
+pub struct UploadOptions {
+    /// Removes the deployment automatically once this has passed
+    pub ttl: Option<Duration>,
+    /// Lets the server recognise a retried upload and answer with the original result
+    pub idempotency_key: Option<String>,
+    /// Replaces deployments holding the same domain instead of failing
+    pub force: bool,
+    /// Lets the upload move the deployment to another domain, see [`DomainChange`]
+    ///
+    /// [`DomainChange`]: crate::shared::DomainChange
+    pub overwrite: bool,
+    /// Asks the server to report the Caddy route it generated for the bundle
+    pub verbose: bool,
+    /// Marks the archive as gzip compressed, see [`gzip`]
+    pub gzip: bool,
+    /// Only prepares the bundle on the server, it goes live once [`activate`] is called
+    pub prepare: bool,
+}
+
+/// Symlink within the build root which was left out of an archive
+#[derive(Debug, Clone)]
+pub struct SkippedLink {
+    pub path: PathBuf,
+    pub reason: LinkIssue,
+}
+
+/// Reason why a symlink can not be archived
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkIssue {
+    /// The target does not exist
+    Dangling,
+    /// The target lies outside of the build root and links are not followed
+    Outside,
+    /// Following the link leads back to one of its parents
+    Loop,
+}
+
+impl LinkIssue {
+    /// Short identifier for machine readable output
+    pub fn name(self) -> &'static str {
+        match self {
+            LinkIssue::Dangling => "dangling",
+            LinkIssue::Outside => "outside",
+            LinkIssue::Loop => "loop",
+        }
+    }
+}
+
+impl std::fmt::Display for LinkIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LinkIssue::Dangling => "is dangling",
+            LinkIssue::Outside => "points outside of the build root",
+            LinkIssue::Loop => "links to one of its parents",
+        })
+    }
+}
+
+/// Packs the bundle config and all files within `root` into a tar archive at `destination`
+///
+/// The config is written as the first entry, named [`CONFIG_ENTRY`], so the server can read
+/// it without scanning the whole archive. A file of the same name in `root` is left out.
+/// Symlinks are stored as relative links to their resolved target unless `follow_symlinks` is
+/// set, in which case their targets are archived in their place. Links which are dangling, or
+/// which point outside of `root` without being followed, are skipped and reported instead of
+/// failing the whole archive.
+/// The returned file is rewound to the start and ready to be uploaded.
+pub fn bundle(
+    config: &BundleConfig,
+    root: &Path,
+    destination: &Path,
+    follow_symlinks: bool,
+) -> Result<(File, Vec<SkippedLink>)> {
+    let temp = temp_dir::TempDir::new().context("failed to create temp dir")?;
+    let path_meta = temp.child(CONFIG_ENTRY);
+
+    let meta = serde_json::to_string(config).map_err(|e| ClientError::Config(e.to_string()))?;
+    std::fs::write(&path_meta, meta).context("failed to write metadata")?;
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(destination)
+        .context("failed to create archive file")?;
+
+    let mut skipped = Vec::new();
+
+    {
+        let mut buf_wrt = BufWriter::new(&mut file);
+        let mut builder = tar::Builder::new(&mut buf_wrt);
+        builder.follow_symlinks(follow_symlinks);
+
+        builder
+            .append_path_with_name(path_meta, Path::new(".").join(CONFIG_ENTRY))
+            .context("failed to add launch config to archive")?;
+
+        let canonical_root = root
+            .canonicalize()
+            .context("failed to add files to archive")?;
+
+        for entry in WalkDir::new(root)
+            .min_depth(1)
+            .follow_links(follow_symlinks)
+        {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => match e.path().filter(|path| path.is_symlink()) {
+                    Some(path) => {
+                        skipped.push(SkippedLink {
+                            path: path.to_path_buf(),
+                            reason: if e.loop_ancestor().is_some() {
+                                LinkIssue::Loop
+                            } else {
+                                LinkIssue::Dangling
+                            },
+                        });
+                        continue;
+                    }
+                    None => {
+                        return Err(io::Error::from(e)).context("failed to add files to archive")
+                    }
+                },
+            };
+
+            let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+            let name = Path::new(".").join(relative);
+
+            if relative == Path::new(CONFIG_ENTRY) {
+                continue;
+            }
+
+            if entry.path_is_symlink() && !follow_symlinks {
+                match archived_link(&canonical_root, entry.path(), relative) {
+                    // Absolute targets or those passing through other links would not resolve
+                    // the same way on the server, so links point straight at their target
+                    Ok(target) => {
+                        let mut header = tar::Header::new_gnu();
+                        header.set_metadata(
+                            &entry
+                                .path()
+                                .symlink_metadata()
+                                .context("failed to add files to archive")?,
+                        );
+                        builder
+                            .append_link(&mut header, name, target)
+                            .context("failed to add files to archive")?;
+                    }
+                    Err(reason) => skipped.push(SkippedLink {
+                        path: entry.path().to_path_buf(),
+                        reason,
+                    }),
+                }
+
+                continue;
+            }
+
+            if entry.file_type().is_dir() {
+                builder.append_dir(name, entry.path())
+            } else {
+                builder.append_path_with_name(entry.path(), name)
+            }
+            .context("failed to add files to archive")?;
+        }
+
+        builder.finish().context("failed to finalise archive")?;
+    }
+
+    file.seek(SeekFrom::Start(0))
+        .context("failed to seek through archive")?;
+
+    Ok((file, skipped))
+}
+
+/// Target a symlink at `path`, which is `relative` to the canonical `root`, is archived with
+///
+/// Links point straight at their resolved target, relative to the directory holding them.
+pub fn archived_link(root: &Path, path: &Path, relative: &Path) -> Result<PathBuf, LinkIssue> {
+    let target = path.canonicalize().map_err(|_| LinkIssue::Dangling)?;
+    let target = target.strip_prefix(root).map_err(|_| LinkIssue::Outside)?;
+
+    Ok(relative_link(
+        relative.parent().unwrap_or(Path::new("")),
+        target,
+    ))
+}
+
+/// Path leading from the directory `from` to `to`, both relative to the same root
+fn relative_link(from: &Path, to: &Path) -> PathBuf {
+    let from = from.components().collect::<Vec<_>>();
+    let to = to.components().collect::<Vec<_>>();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+
+    let mut link = PathBuf::new();
+    from[common..].iter().for_each(|_| link.push(".."));
+    to[common..]
+        .iter()
+        .for_each(|component| link.push(component));
+
+    if link.as_os_str().is_empty() {
+        link.push(".");
+    }
+
+    link
+}
+
+/// Uploads a bundle archive of the given size and waits for the server to deploy it
+///
+/// Archives larger than [`CHUNK_SIZE`] are transferred in chunks which are retried individually.
+pub fn upload<R: Read + Seek>(
+    endpoint: &str,
+    id: Ulid,
+    archive: &mut R,
+    size: u64,
+    options: &UploadOptions,
+) -> Result<Deployment> {
+    let req_path = format!("{endpoint}/bundle/{id}");
+
+    let response = if size > CHUNK_SIZE {
+        upload_chunked(&req_path, archive, size, options)?
+    } else {
+        let mut request = options.apply(agent().post(&req_path));
+
+        if options.gzip {
+            request = request.set("Content-Encoding", "gzip");
+        }
+
+        request.send(archive).context("failed to upload bundle")?
+    };
+
+    parse(response)
+}
+
+fn upload_chunked<R: Read + Seek>(
+    req_path: &str,
+    archive: &mut R,
+    size: u64,
+    options: &UploadOptions,
+) -> Result<ureq::Response> {
+    let count = size.div_ceil(CHUNK_SIZE);
+
+    for index in 0..count {
+        let mut attempt = 1;
+
+        loop {
+            archive
+                .seek(SeekFrom::Start(index * CHUNK_SIZE))
+                .context("failed to read archive")?;
+
+            let chunk_path = format!("{req_path}/chunk/{index}");
+            match agent().post(&chunk_path).send(archive.take(CHUNK_SIZE)) {
+                Ok(_) => break,
+                Err(ureq::Error::Transport(_)) if attempt < CHUNK_ATTEMPTS => attempt += 1,
+                Err(e) => return Err(e).context("failed to upload chunk"),
+            }
+        }
+    }
+
+    let mut request = options.apply(agent().post(&format!("{req_path}/commit")));
+
+    // The chunks carry the encoded archive, while the commit itself is plain JSON
+    if options.gzip {
+        request = request.set(ARCHIVE_ENCODING_HEADER, "gzip");
+    }
+
+    request.send_json(count).context("failed to commit chunks")
+}
+
+impl UploadOptions {
+    fn apply(&self, mut request: ureq::Request) -> ureq::Request {
+        if let Some(ttl) = self.ttl {
+            request = request.set(TTL_HEADER, &ttl.as_secs().to_string());
+        }
+
+        if let Some(key) = &self.idempotency_key {
+            request = request.set(IDEMPOTENCY_HEADER, key);
+        }
+
+        if self.force {
+            request = request.set(FORCE_HEADER, "1");
+        }
+
+        if self.overwrite {
+            request = request.set(OVERWRITE_HEADER, "1");
+        }
+
+        if self.verbose {
+            request = request.set(VERBOSE_HEADER, "1");
+        }
+
+        if self.prepare {
+            request = request.set(PREPARE_HEADER, "1");
+        }
+
+        request
+    }
+}
+
+/// Whether the server accepts gzip compressed uploads, which older servers do not
+pub fn accepts_gzip(endpoint: &str) -> Result<bool> {
+    match agent().head(endpoint).call() {
+        Ok(response) => Ok(response
+            .header("Accept-Encoding")
+            .map(|encodings| encodings.split(',').any(|e| e.trim() == "gzip"))
+            .unwrap_or_default()),
+        Err(ureq::Error::Status(..)) => Ok(false),
+        Err(error) => Err(error).context("http req failed"),
+    }
+}
+
+/// Compresses an archive with gzip into `destination`, rewinding both files
+pub fn gzip(archive: &mut File, destination: &Path) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(destination)
+        .context("failed to create archive file")?;
+
+    archive
+        .seek(SeekFrom::Start(0))
+        .context("failed to seek through archive")?;
+
+    {
+        let mut encoder = GzEncoder::new(BufWriter::new(&mut file), Compression::default());
+        io::copy(archive, &mut encoder).context("failed to compress archive")?;
+        encoder
+            .finish()
+            .and_then(|mut writer| writer.flush())
+            .context("failed to compress archive")?;
+    }
+
+    archive
+        .seek(SeekFrom::Start(0))
+        .context("failed to seek through archive")?;
+    file.seek(SeekFrom::Start(0))
+        .context("failed to seek through archive")?;
+
+    Ok(file)
+}
+
+/// Generates the idempotency key of one upload attempt, which all of its retries share
+///
+/// Keys are unique per attempt instead of being derived from the archive, so uploading the
+/// same archive again after the deployment changed is deployed anew rather than replayed.
+pub fn idempotency_key() -> String {
+    Ulid::new().to_string()
+}
+
+/// Bundles the files within `root` and deploys them under the given id
+pub fn deploy(endpoint: &str, id: Ulid, config: &BundleConfig, root: &Path) -> Result<Deployment> {
+    let temp = temp_dir::TempDir::new().context("failed to create temp dir")?;
+    let (mut archive, _) = bundle(config, root, &temp.child("launch.bundle.tar"), false)?;
+    let size = archive
+        .metadata()
+        .context("failed to read archive metadata")?
+        .len();
+
+    let options = UploadOptions {
+        idempotency_key: Some(idempotency_key()),
+        ..Default::default()
+    };
+
+    upload(endpoint, id, &mut archive, size, &options)
+}
+
+/// Fetches the version and capabilities of the server
+pub fn version(endpoint: &str) -> Result<VersionInfo> {
+    agent()
+        .get(&format!("{endpoint}/version"))
+        .call()
+        .context("http req failed")
+        .and_then(parse::<VersionInfo>)
+}
+
+/// Fetches all deployments known to the server
+pub fn list(endpoint: &str) -> Result<HashMap<Ulid, Bundle>> {
+    agent()
+        .get(endpoint)
+        .call()
+        .context("http req failed")
+        .and_then(parse::<HashMap<Ulid, Bundle>>)
+}
+
+/// Deployments of a server as they are parsed from the response
+pub type BundleStream = Box<dyn Iterator<Item = Result<(Ulid, Bundle)>>>;
+
+/// Streams all deployments known to the server, parsing them one at a time
+///
+/// Servers which do not support newline delimited listings are read in one piece instead.
+/// The `query` narrows down the listing on the server, e.g. `[("state", "failed")]`.
+pub fn list_stream(endpoint: &str, query: &[(&str, &str)]) -> Result<BundleStream> {
+    let response = agent()
+        .get(endpoint)
+        .query_pairs(query.iter().copied())
+        .set("Accept", &format!("{NDJSON}, application/json"))
+        .call()
+        .context("http req failed")?;
+
+    if response.content_type() != NDJSON {
+        return Ok(Box::new(
+            parse::<HashMap<Ulid, Bundle>>(response)?
+                .into_iter()
+                .map(Ok),
+        ));
+    }
+
+    let lines = BufReader::new(response.into_reader()).lines();
+
+    Ok(Box::new(
+        lines
+            .filter(|line| !matches!(line, Ok(line) if line.is_empty()))
+            .map(|line| {
+                let line = line.map_err(|source| ClientError::Response {
+                    action: "failed to read response",
+                    source,
+                })?;
+
+                serde_json::from_str::<ListEntry>(&line)
+                    .map(|entry| (entry.id, entry.bundle))
+                    .map_err(|e| ClientError::Response {
+                        action: "failed to deserialize response",
+                        source: e.into(),
+                    })
+            }),
+    ))
+}
+
+/// Removes a deployment from the server
+pub fn delete(endpoint: &str, id: Ulid) -> Result<()> {
+    agent()
+        .delete(&format!("{endpoint}/bundle/{id}"))
+        .call()
+        .context("failed to delete deployment")?;
+
+    Ok(())
+}
+
+/// Serves the prepared version of a deployment in place of the current one
+pub fn activate(endpoint: &str, id: Ulid, force: bool) -> Result<Deployment> {
+    let mut request = agent().post(&format!("{endpoint}/bundle/{id}/activate"));
+
+    if force {
+        request = request.set(FORCE_HEADER, "1");
+    }
+
+    request
+        .call()
+        .context("failed to activate deployment")
+        .and_then(parse::<Deployment>)
+}
+
+/// Changes fields of the config of an active deployment, returning the resulting config
+///
+/// Only top-level fields present in `changes` are replaced.
+pub fn update(endpoint: &str, id: Ulid, changes: &Value) -> Result<BundleConfig> {
+    agent()
+        .request("PATCH", &format!("{endpoint}/bundle/{id}"))
+        .send_json(changes)
+        .context("failed to update deployment")
+        .and_then(parse::<BundleConfig>)
+}
+
+/// Removes all deployments which failed to load, returning how many were removed
+pub fn prune(endpoint: &str) -> Result<usize> {
+    agent()
+        .post(&format!("{endpoint}/prune"))
+        .call()
+        .context("failed to prune deployments")
+        .and_then(parse::<usize>)
+}
+
+/// Whether the server answers all requests with its maintenance page
+pub fn maintenance(endpoint: &str) -> Result<bool> {
+    agent()
+        .get(&format!("{endpoint}/maintenance"))
+        .call()
+        .context("http req failed")
+        .and_then(parse::<bool>)
+}
+
+/// Switches maintenance mode on or off, returning the new state
+pub fn set_maintenance(endpoint: &str, enabled: bool) -> Result<bool> {
+    let url = format!("{endpoint}/maintenance");
+    let request = match enabled {
+        true => agent().post(&url),
+        false => agent().delete(&url),
+    };
+
+    request
+        .call()
+        .context("failed to switch maintenance mode")
+        .and_then(parse::<bool>)
+}
+
+/// Fetches the deployment history of a domain, oldest first
+pub fn history(endpoint: &str, domain: &str) -> Result<Vec<HistoryEntry>> {
+    agent()
+        .get(&format!("{endpoint}/history/{domain}"))
+        .call()
+        .context("http req failed")
+        .and_then(parse::<Vec<HistoryEntry>>)
+}
+
+/// Downloads the archive of a deployment as it was uploaded, returning its size
+pub fn download(endpoint: &str, id: Ulid, destination: &Path) -> Result<u64> {
+    let response = agent()
+        .get(&format!("{endpoint}/bundle/{id}/archive"))
+        .call()
+        .context("http req failed")?;
+
+    let mut file = File::create(destination).context("failed to create archive file")?;
+    let size = std::io::copy(&mut response.into_reader(), &mut file)
+        .context("failed to download archive")?;
+    file.sync_all().context("failed to download archive")?;
+
+    Ok(size)
+}
+
+/// Fetches recent server events of a deployment, starting at the given sequence number
+pub fn logs(endpoint: &str, id: Ulid, since: u64) -> Result<Vec<LogEntry>> {
+    agent()
+        .get(&format!("{endpoint}/bundle/{id}/logs"))
+        .query("since", &since.to_string())
+        .call()
+        .context("http req failed")
+        .and_then(parse::<Vec<LogEntry>>)
+}
+
+/// Lists the files served by an active deployment
+pub fn files(endpoint: &str, id: Ulid) -> Result<Vec<ManifestEntry>> {
+    agent()
+        .get(&format!("{endpoint}/bundle/{id}/files"))
+        .call()
+        .context("http req failed")
+        .and_then(parse::<Vec<ManifestEntry>>)
+}
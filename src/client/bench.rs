@@ -0,0 +1,112 @@
+use super::new_table;
+use crate::server::{is_compressible, Algorithm};
+use crate::shared::BundleConfig;
+use anyhow::{bail, Context, Result};
+use comfy_table::{Cell, CellAlignment};
+use indicatif::{FormattedDuration, HumanBytes};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+
+/// Levels compared per algorithm, from fast to the one used for deployments
+const LEVELS: [(Algorithm, &[u32]); 2] = [
+    (Algorithm::Gzip, &[1, 6, 9]),
+    (Algorithm::Brotli, &[4, 9, 11]),
+];
+
+/// Compresses the files of a build root at several levels and compares ratio and wall time
+pub fn run(config: Option<&BundleConfig>, root: &Path) -> Result<()> {
+    let files = compressible(config, root).context("failed to read build root")?;
+    let size = files.iter().map(|f| f.len() as u64).sum::<u64>();
+
+    if files.is_empty() {
+        bail!("no compressible files found in {}", root.display());
+    }
+
+    println!(
+        "Compressing {} files, {} in total",
+        files.len(),
+        HumanBytes(size)
+    );
+
+    let brotli = config.map(|c| c.brotli.clone()).unwrap_or_default();
+
+    let mut table = new_table();
+    table.set_header(vec![
+        Cell::new("Algorithm"),
+        Cell::new("Level").set_alignment(CellAlignment::Right),
+        Cell::new("Size").set_alignment(CellAlignment::Right),
+        Cell::new("Ratio").set_alignment(CellAlignment::Right),
+        Cell::new("Time").set_alignment(CellAlignment::Right),
+    ]);
+
+    for (algorithm, levels) in LEVELS {
+        for level in levels {
+            let start = Instant::now();
+            let mut compressed = 0;
+
+            for file in &files {
+                let mut output = Vec::new();
+                algorithm.compress_at(*level, file, &mut output, &brotli)?;
+                compressed += output.len() as u64;
+            }
+
+            let elapsed = start.elapsed();
+
+            table.add_row(vec![
+                Cell::new(algorithm.name()),
+                Cell::new(level).set_alignment(CellAlignment::Right),
+                Cell::new(HumanBytes(compressed)).set_alignment(CellAlignment::Right),
+                Cell::new(format!("{:.1}%", compressed as f64 / size as f64 * 100.0))
+                    .set_alignment(CellAlignment::Right),
+                Cell::new(time(elapsed)).set_alignment(CellAlignment::Right),
+            ]);
+        }
+    }
+
+    println!("\n{table}");
+
+    Ok(())
+}
+
+/// Contents of files the server would precompress, limited to the configured extensions if any
+fn compressible(config: Option<&BundleConfig>, root: &Path) -> io::Result<Vec<Vec<u8>>> {
+    let mut files = Vec::new();
+
+    for entry in WalkDir::new(root) {
+        let entry = entry?;
+
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        if let Some(config) = config {
+            let extension = entry.path().extension().unwrap_or_default();
+            if !config
+                .compress
+                .iter()
+                .any(|e| extension.eq_ignore_ascii_case(e))
+            {
+                continue;
+            }
+        }
+
+        let data = fs::read(entry.path())?;
+
+        if is_compressible(&data) {
+            files.push(data);
+        }
+    }
+
+    Ok(files)
+}
+
+fn time(elapsed: Duration) -> String {
+    if elapsed < Duration::from_secs(1) {
+        format!("{}ms", elapsed.as_millis())
+    } else {
+        FormattedDuration(elapsed).to_string()
+    }
+}
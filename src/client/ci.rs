@@ -0,0 +1,92 @@
+use super::LaunchConfig;
+use clap::ValueEnum;
+use std::path::Component;
+
+const INSTALL: &str =
+    "cargo install --locked --features client --git https://github.com/TilBlechschmidt/launch";
+
+/// CI systems a starter deployment job can be generated for
+#[derive(Clone, Copy, ValueEnum)]
+pub enum CiProvider {
+    Github,
+    Gitlab,
+}
+
+/// Renders a deployment job for the given config, ready to be pasted into the CI config
+pub fn job(provider: CiProvider, config: &LaunchConfig) -> String {
+    let url = config.bundle.url();
+
+    // `launch it` runs the configured build command by itself
+    let build = match &config.build {
+        Some(_) => None,
+        None => Some(format!(
+            "echo 'Replace this with the command that builds {}'",
+            config.root.display()
+        )),
+    };
+
+    // The whole project is only deployed when asked for, which CI cannot answer interactively
+    let launch = if config.root.components().all(|c| c == Component::CurDir) {
+        "launch it --porcelain --yes"
+    } else {
+        "launch it --porcelain"
+    };
+
+    match provider {
+        CiProvider::Github => {
+            let build = build
+                .map(|build| format!("      - name: Build\n        run: {build}\n"))
+                .unwrap_or_default();
+
+            format!(
+                "# .github/workflows/launch.yml
+# Deploys {name} to {url}
+name: Launch
+
+on:
+  push:
+    branches: [main]
+
+jobs:
+  launch:
+    runs-on: ubuntu-latest
+    environment:
+      name: production
+      url: {url}
+    steps:
+      - uses: actions/checkout@v4
+      - name: Install launch
+        run: {INSTALL}
+{build}      - name: Launch
+        run: {launch}
+        env:
+          LAUNCH_ENDPOINT: ${{{{ secrets.LAUNCH_ENDPOINT }}}}
+",
+                name = config.bundle.name,
+            )
+        }
+        CiProvider::Gitlab => {
+            let build = build
+                .map(|build| format!("    - {build}\n"))
+                .unwrap_or_default();
+
+            format!(
+                "# .gitlab-ci.yml
+# Deploys {name} to {url}, set LAUNCH_ENDPOINT as a masked CI/CD variable
+launch:
+  stage: deploy
+  image: rust:latest
+  environment:
+    name: production
+    url: {url}
+  rules:
+    - if: $CI_COMMIT_BRANCH == $CI_DEFAULT_BRANCH
+  script:
+    - {INSTALL}
+{build}    - {launch}
+",
+                name = config.bundle.name,
+            )
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use super::new_table;
+use crate::{api::archived_link, shared::CONFIG_ENTRY};
+use anyhow::{Context, Result};
+use comfy_table::{Cell, CellAlignment, Color};
+use indicatif::HumanBytes;
+use std::collections::{hash_map::DefaultHasher, BTreeMap};
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{self, Read};
+use std::path::{Component, Path};
+use walkdir::WalkDir;
+
+/// Size and content digest of a file, keyed by its path relative to the bundle root
+///
+/// Symlinks are listed with a size of zero and the digest of their target.
+type Listing = BTreeMap<String, (u64, u64)>;
+
+/// Compares the files of a deployed archive with those of the local build root
+///
+/// Symlinks are treated like `launch it` does with the same `follow_symlinks`.
+pub fn compare(archive: File, root: &Path, follow_symlinks: bool) -> Result<()> {
+    let deployed = deployed(archive).context("failed to read deployed archive")?;
+    let local = local(root, follow_symlinks).context("failed to read build root")?;
+
+    let mut table = new_table();
+    table.set_header(vec![
+        Cell::new(""),
+        Cell::new("Path"),
+        Cell::new("Size").set_alignment(CellAlignment::Right),
+    ]);
+
+    let (mut added, mut removed, mut changed) = (0, 0, 0);
+
+    for (path, (size, _)) in deployed.iter() {
+        if !local.contains_key(path) {
+            removed += 1;
+            table.add_row(vec![
+                Cell::new("-").fg(Color::Red),
+                Cell::new(path).fg(Color::Red),
+                Cell::new(delta(*size, 0)).set_alignment(CellAlignment::Right),
+            ]);
+        }
+    }
+
+    for (path, (size, digest)) in local.iter() {
+        match deployed.get(path) {
+            None => {
+                added += 1;
+                table.add_row(vec![
+                    Cell::new("+").fg(Color::Green),
+                    Cell::new(path).fg(Color::Green),
+                    Cell::new(delta(0, *size)).set_alignment(CellAlignment::Right),
+                ]);
+            }
+            Some((previous, previous_digest)) if previous_digest != digest => {
+                changed += 1;
+                table.add_row(vec![
+                    Cell::new("~").fg(Color::Yellow),
+                    Cell::new(path).fg(Color::Yellow),
+                    Cell::new(delta(*previous, *size)).set_alignment(CellAlignment::Right),
+                ]);
+            }
+            Some(_) => {}
+        }
+    }
+
+    if added + removed + changed == 0 {
+        println!("The build root matches the deployment, nothing to launch");
+        return Ok(());
+    }
+
+    let before = deployed.values().map(|(size, _)| size).sum();
+    let after = local.values().map(|(size, _)| size).sum();
+
+    println!("\n{table}\n");
+    println!(
+        "{added} added, {removed} removed, {changed} changed, net {}",
+        delta(before, after)
+    );
+
+    Ok(())
+}
+
+fn deployed(archive: File) -> io::Result<Listing> {
+    let mut listing = Listing::new();
+    let mut archive = tar::Archive::new(archive);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let kind = entry.header().entry_type();
+
+        if !kind.is_file() && !kind.is_symlink() {
+            continue;
+        }
+
+        let path = entry
+            .path()?
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(c) => c.to_str(),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+
+        if path == CONFIG_ENTRY {
+            continue;
+        }
+
+        let listed = match entry.link_name()? {
+            Some(target) if kind.is_symlink() => (0, link_digest(&target)),
+            _ => (entry.size(), digest(&mut entry)?),
+        };
+
+        listing.insert(path, listed);
+    }
+
+    Ok(listing)
+}
+
+fn local(root: &Path, follow_symlinks: bool) -> io::Result<Listing> {
+    let mut listing = Listing::new();
+    let canonical_root = root.canonicalize()?;
+
+    for entry in WalkDir::new(root).follow_links(follow_symlinks) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            // Links which can not be followed are left out of the upload as well
+            Err(e) if e.path().is_some_and(Path::is_symlink) => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+        let path = relative.to_string_lossy().replace('\\', "/");
+
+        if entry.path_is_symlink() && !follow_symlinks {
+            // Links the upload would skip are not part of the deployment either
+            if let Ok(target) = archived_link(&canonical_root, entry.path(), relative) {
+                listing.insert(path, (0, link_digest(&target)));
+            }
+
+            continue;
+        }
+
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let size = entry.metadata()?.len();
+        listing.insert(path, (size, digest(&mut File::open(entry.path())?)?));
+    }
+
+    Ok(listing)
+}
+
+fn digest(reader: &mut impl Read) -> io::Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    let mut buffer = [0; 64 * 1024];
+
+    loop {
+        let read = reader.read(&mut buffer)?;
+
+        if read == 0 {
+            break;
+        }
+
+        hasher.write(&buffer[..read]);
+    }
+
+    Ok(hasher.finish())
+}
+
+fn link_digest(target: &Path) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(target.to_string_lossy().replace('\\', "/").as_bytes());
+    hasher.finish()
+}
+
+/// Signed size difference, e.g. `+1.20 KiB`
+fn delta(before: u64, after: u64) -> String {
+    if after >= before {
+        format!("+{}", HumanBytes(after - before))
+    } else {
+        format!("-{}", HumanBytes(before - after))
+    }
+}
@@ -0,0 +1,76 @@
+use crate::api::ClientError;
+
+/// Exit status of the client, stable so CI pipelines can branch on the kind of failure
+///
+/// | Code | Meaning                                             |
+/// |------|-----------------------------------------------------|
+/// | 0    | Success                                             |
+/// | 1    | Any failure not covered below                       |
+/// | 2    | The launch config is missing or invalid             |
+/// | 3    | The server could not be reached                     |
+/// | 4    | The server rejected the request (4xx)               |
+/// | 5    | The server failed to handle the request (5xx)       |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    Failure = 1,
+    Config = 2,
+    Network = 3,
+    Rejected = 4,
+    Server = 5,
+}
+
+/// Listing of the exit codes for the command line help
+pub const EXIT_CODES_HELP: &str = "Exit codes:
+  1  any failure not covered below
+  2  the launch config is missing or invalid
+  3  the server could not be reached
+  4  the server rejected the request (4xx)
+  5  the server failed to handle the request (5xx)";
+
+impl ExitCode {
+    /// Classifies an error by the first code or client error found in its chain
+    pub fn of(error: &anyhow::Error) -> Self {
+        error
+            .chain()
+            .find_map(|cause| {
+                if let Some(code) = cause.downcast_ref::<ExitCode>() {
+                    Some(*code)
+                } else {
+                    match cause.downcast_ref::<ClientError>()? {
+                        ClientError::Config(_) => Some(ExitCode::Config),
+                        ClientError::Network { .. } => Some(ExitCode::Network),
+                        ClientError::Status { code, .. } => Some(ExitCode::from_status(*code)),
+                        ClientError::Archive { .. } | ClientError::Response { .. } => None,
+                    }
+                }
+            })
+            .unwrap_or(ExitCode::Failure)
+    }
+
+    pub fn from_status(status: u16) -> Self {
+        match status {
+            400..=499 => ExitCode::Rejected,
+            _ => ExitCode::Server,
+        }
+    }
+}
+
+impl std::fmt::Display for ExitCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ExitCode::Failure => "launch failed",
+            ExitCode::Config => "invalid launch config",
+            ExitCode::Network => "server unreachable",
+            ExitCode::Rejected => "request rejected by the server",
+            ExitCode::Server => "server failed to handle the request",
+        })
+    }
+}
+
+impl std::error::Error for ExitCode {}
+
+impl From<ExitCode> for std::process::ExitCode {
+    fn from(code: ExitCode) -> Self {
+        std::process::ExitCode::from(code as u8)
+    }
+}
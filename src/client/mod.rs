@@ -1,25 +1,41 @@
+mod bench;
+mod ci;
+mod diff;
+mod exit;
+mod preview;
+
+pub use exit::{ExitCode, EXIT_CODES_HELP};
+
+use crate::api;
 use crate::server::{Algorithm, Statistics};
-use crate::shared::{Bundle, BundleConfig};
+use crate::shared::{
+    Bundle, BundleConfig, CompressionProfile, Deployment, DomainChange, DomainConflict,
+    ManifestEntry, Redirect,
+};
 use anyhow::{anyhow, bail, Context, Result};
-use clap::{Args, Subcommand};
+use clap::{Args, Subcommand, ValueEnum};
 use comfy_table::*;
 use console::style;
 use git2::{Repository, RepositoryOpenFlags};
 use indicatif::{
-    FormattedDuration, HumanBytes, HumanDuration, ProgressBar, ProgressState, ProgressStyle,
+    FormattedDuration, HumanBytes, HumanDuration, ProgressBar, ProgressDrawTarget, ProgressState,
+    ProgressStyle,
 };
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 use std::env::current_dir;
 use std::fmt::Write;
-use std::fs::{File, OpenOptions};
-use std::io::{BufWriter, Read, Seek, SeekFrom};
-use std::path::PathBuf;
-use std::time::Duration;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use ulid::Ulid;
 
 const LAUNCH_FILE_NAME: &str = "launch.json";
 
+/// Config file passed on the command line, taking the place of [`LAUNCH_FILE_NAME`]
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
 #[derive(Subcommand)]
 pub enum Command {
     /// Bootstraps the current folder for deployment
@@ -30,12 +46,46 @@ pub enum Command {
     List {
         #[arg(short, long, env = "LAUNCH_ENDPOINT")]
         endpoint: String,
+
+        /// Only lists deployments carrying this label, e.g. `team=web`
+        #[arg(short, long, value_parser = parse_label)]
+        label: Vec<(String, String)>,
+
+        /// Only lists deployments whose domain contains this text
+        #[arg(short, long)]
+        filter: Option<String>,
+
+        /// Only lists deployments in this state
+        #[arg(short, long, value_parser = ["prepared", "active", "failed"])]
+        state: Option<String>,
     },
 
     /// Launches it (pushes the current repository)
-    It {
+    It(LaunchOptions),
+
+    /// Serves the build root locally like a deployment would
+    Preview,
+
+    /// Validates the launch config and build root, without contacting a server
+    Check,
+
+    /// Compares compression levels on the build root, without contacting a server
+    Bench {
+        /// Directory to compress, defaults to the configured build root
+        path: Option<PathBuf>,
+    },
+
+    /// Serves a deployment prepared with `launch it --prepare` in place of the current one
+    Activate {
         #[arg(short, long, env = "LAUNCH_ENDPOINT")]
         endpoint: String,
+
+        /// Deployment to activate, will be inferred from the current dir if left blank
+        id: Option<Ulid>,
+
+        /// Replaces the deployment currently holding the domain instead of failing
+        #[arg(long)]
+        force: bool,
     },
 
     /// Removes the current repository if it is deployed
@@ -46,12 +96,110 @@ pub enum Command {
         /// Deployment to delete, will be inferred from the current dir if left blank
         id: Option<Ulid>,
     },
+
+    /// Changes the friendly name of a live deployment without redeploying it
+    Rename {
+        #[arg(short, long, env = "LAUNCH_ENDPOINT")]
+        endpoint: String,
+
+        /// Deployment to rename
+        id: Ulid,
+
+        /// New name of the deployment
+        name: String,
+    },
+
+    /// Shows previous deployments to a domain and how well they compressed
+    History {
+        #[arg(short, long, env = "LAUNCH_ENDPOINT")]
+        endpoint: String,
+
+        /// Domain to show, will be inferred from the current dir if left blank
+        #[arg(short, long)]
+        domain: Option<String>,
+    },
+
+    /// Downloads the archive of a deployment as it was uploaded
+    Download {
+        #[arg(short, long, env = "LAUNCH_ENDPOINT")]
+        endpoint: String,
+
+        /// Deployment to download, will be inferred from the current dir if left blank
+        id: Option<Ulid>,
+
+        /// Where to write the archive
+        #[arg(short, long, default_value = "launch.bundle.tar")]
+        output: PathBuf,
+    },
+
+    /// Compares the local build root with the files of the deployment
+    Diff {
+        #[arg(short, long, env = "LAUNCH_ENDPOINT")]
+        endpoint: String,
+
+        /// Deployment to compare against, will be inferred from the current dir if left blank
+        id: Option<Ulid>,
+
+        /// Compares the targets of symlinks instead of the links, as `launch it --follow-symlinks`
+        #[arg(long)]
+        follow_symlinks: bool,
+    },
+
+    /// Lists the files of a deployment and how well each of them compressed
+    Files {
+        #[arg(short, long, env = "LAUNCH_ENDPOINT")]
+        endpoint: String,
+
+        /// Deployment to list, will be inferred from the current dir if left blank
+        id: Option<Ulid>,
+
+        /// Sorts the files which compressed worst to the top instead of sorting by path
+        #[arg(short, long)]
+        worst: bool,
+    },
+
+    /// Shows recent server events of a deployment
+    Logs {
+        #[arg(short, long, env = "LAUNCH_ENDPOINT")]
+        endpoint: String,
+
+        /// Deployment to show, will be inferred from the current dir if left blank
+        id: Option<Ulid>,
+
+        /// Keeps polling for new events until interrupted
+        #[arg(short, long)]
+        follow: bool,
+    },
+
+    /// Removes all deployments which failed to load
+    Prune {
+        #[arg(short, long, env = "LAUNCH_ENDPOINT")]
+        endpoint: String,
+    },
+
+    /// Serves a maintenance page for all deployments, or restores them
+    Maintenance {
+        #[arg(short, long, env = "LAUNCH_ENDPOINT")]
+        endpoint: String,
+
+        /// Leave out to show whether maintenance mode is on
+        #[arg(value_enum)]
+        state: Option<Switch>,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Switch {
+    On,
+    Off,
 }
 
 #[derive(Args)]
 pub struct InitOptions {
-    name: String,
-    domain: String,
+    #[arg(required_unless_present = "print_ci")]
+    name: Option<String>,
+    #[arg(required_unless_present = "print_ci")]
+    domain: Option<String>,
 
     /// Location of the build root, usually something like `dist` or `build`. Relative to project root!
     #[arg(short, long)]
@@ -64,6 +212,92 @@ pub struct InitOptions {
     /// Reinitialize the config, disconnecting it from deployed instances
     #[arg(long)]
     force: bool,
+
+    /// Prints a deployment job for the given CI system, using the existing config if present
+    #[arg(long, value_enum)]
+    print_ci: Option<ci::CiProvider>,
+}
+
+/// Options shared by all client commands
+#[derive(Args)]
+pub struct GlobalOptions {
+    /// Launch config to use instead of the `launch.json` in the project root
+    #[arg(long, global = true, env = "LAUNCH_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// How long requests may stall before giving up, e.g. `30s` or `5m`
+    #[arg(long, global = true, env = "LAUNCH_TIMEOUT", value_parser = parse_duration)]
+    timeout: Option<Duration>,
+}
+
+#[derive(Args)]
+pub struct LaunchOptions {
+    #[arg(short, long, env = "LAUNCH_ENDPOINT")]
+    endpoint: String,
+
+    /// Opens the deployment in the default browser once it is live
+    #[arg(long)]
+    open: bool,
+
+    /// Aborts instead of warning when the bundle exceeds the configured `max_size`
+    #[arg(long)]
+    strict_budget: bool,
+
+    /// Removes the deployment automatically after this long, e.g. `24h` or `30m`
+    #[arg(long, value_parser = parse_duration)]
+    ttl: Option<Duration>,
+
+    /// Shell command to build the project with, overrides `build` from the launch config
+    #[arg(long)]
+    build: Option<String>,
+
+    /// Replaces the deployment currently holding the domain instead of failing
+    #[arg(long)]
+    force: bool,
+
+    /// Moves the deployment to the domain of the upload without asking if it serves another one
+    #[arg(long)]
+    overwrite: bool,
+
+    /// Deploys the build root even if it contains no files
+    #[arg(long)]
+    allow_empty: bool,
+
+    /// Archives the targets of symlinks instead of the links themselves
+    #[arg(long)]
+    follow_symlinks: bool,
+
+    /// Compresses the upload with gzip if the server supports it, saving bandwidth on slow links
+    #[arg(long)]
+    gzip: bool,
+
+    /// Prints the Caddy route the server generated for the bundle
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Reports progress as `key=value` lines on stderr instead of the decorative output
+    #[arg(long)]
+    porcelain: bool,
+
+    /// Uploads and prepares the bundle without serving it, see `launch activate`
+    #[arg(long)]
+    prepare: bool,
+
+    /// Deploys the build root without asking, even if it is the whole project
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Deploys to this domain instead of the configured one, e.g. for previews along with `--id`
+    #[arg(long)]
+    domain: Option<String>,
+
+    /// Names the deployment differently than the launch config does
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Deploys under this id, keeping the deployment of the configured one untouched
+    #[arg(long)]
+    id: Option<Ulid>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -71,6 +305,14 @@ struct LaunchConfig {
     id: Ulid,
     root: PathBuf,
 
+    /// Size budget of the bundle archive, e.g. `10 MiB`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_size: Option<String>,
+
+    /// Shell command run in the project root before bundling, e.g. `npm run build`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    build: Option<String>,
+
     #[serde(flatten)]
     bundle: BundleConfig,
 }
@@ -78,13 +320,18 @@ struct LaunchConfig {
 impl LaunchConfig {
     fn new(options: InitOptions) -> Result<Self> {
         let root = options.root.unwrap_or(".".into());
+        let name = options.name.ok_or_else(|| anyhow!("missing name"))?;
+        let domain = options.domain.ok_or_else(|| anyhow!("missing domain"))?;
 
         Ok(Self {
             id: Ulid::new(),
             root,
+            max_size: None,
+            build: None,
             bundle: BundleConfig {
-                name: options.name,
-                domain: options.domain,
+                name,
+                domain,
+                path: None,
                 compress: vec![
                     "html".into(),
                     "js".into(),
@@ -94,63 +341,297 @@ impl LaunchConfig {
                     "woff2".into(),
                 ],
                 fallback: options.fallback,
+                index: None,
+                try_files: None,
+                not_found: None,
+                browse: false,
+                tls: true,
+                brotli: Default::default(),
+                profile: Default::default(),
+                strip_originals: false,
+                algorithms: None,
+                rate_limit: None,
+                redirects: Vec::new(),
+                mime_types: Default::default(),
+                labels: Default::default(),
+                caddy_extra: None,
             },
         })
     }
 }
 
-pub fn run(command: Command) -> Result<()> {
+pub fn run(command: Command, options: GlobalOptions) -> Result<()> {
+    if std::env::var_os("NO_COLOR").is_some() {
+        console::set_colors_enabled(false);
+        console::set_colors_enabled_stderr(false);
+    }
+
+    if let Some(config) = options.config {
+        CONFIG_PATH.set(config).ok();
+    }
+
+    if let Some(timeout) = options.timeout {
+        api::set_timeout(timeout);
+    }
+
     match command {
-        Command::List { endpoint } => list(&endpoint),
+        Command::List {
+            endpoint,
+            label,
+            filter,
+            state,
+        } => list(&endpoint, &label, filter.as_deref(), state.as_deref()),
         Command::Init(c) => init(c),
-        Command::It { endpoint } => launch(&endpoint),
+        Command::It(o) => launch(o),
+        Command::Activate {
+            endpoint,
+            id,
+            force,
+        } => activate(&endpoint, id, force),
         Command::Deorbit { endpoint, id } => delete(&endpoint, id),
+        Command::Rename { endpoint, id, name } => rename(&endpoint, id, &name),
+        Command::History { endpoint, domain } => history(&endpoint, domain),
+        Command::Prune { endpoint } => prune(&endpoint),
+        Command::Maintenance { endpoint, state } => maintenance(&endpoint, state),
+        Command::Diff {
+            endpoint,
+            id,
+            follow_symlinks,
+        } => diff(&endpoint, id, follow_symlinks),
+        Command::Files {
+            endpoint,
+            id,
+            worst,
+        } => files(&endpoint, id, worst),
+        Command::Logs {
+            endpoint,
+            id,
+            follow,
+        } => logs(&endpoint, id, follow),
+        Command::Download {
+            endpoint,
+            id,
+            output,
+        } => download(&endpoint, id, &output),
+        Command::Preview => {
+            let config = load_config().context("failed to find load config")?;
+            let root = find_build_root(&config).context("failed to find build root")?;
+            preview::serve(&config.bundle, &root)
+        }
+        Command::Check => check(),
+        Command::Bench { path } => {
+            let config = load_config().ok();
+            let root = match (path, &config) {
+                (Some(path), _) => path,
+                (None, Some(config)) => {
+                    find_build_root(config).context("failed to find build root")?
+                }
+                (None, None) => bail!("no launch config found, pass a path to benchmark"),
+            };
+            bench::run(config.as_ref().map(|c| &c.bundle), &root)
+        }
     }
 }
 
-fn init(options: InitOptions) -> Result<()> {
-    let path = find_project_root()?.join(LAUNCH_FILE_NAME);
+fn init(mut options: InitOptions) -> Result<()> {
+    let project_root = find_project_root()?;
+    let path = config_path()?;
+    let print_ci = options.print_ci;
+
+    if let (Some(provider), true, false) = (print_ci, path.exists(), options.force) {
+        print!("{}", ci::job(provider, &load_config()?));
+        return Ok(());
+    }
+
     if path.exists() && !options.force {
+        // A config copied from another project shares its id and would replace its deployment
+        if let Ok(existing) = load_config() {
+            eprintln!(
+                "{} {} {} {}",
+                style("Existing config deploys as").yellow(),
+                style(existing.id).yellow().bold(),
+                style("to").yellow(),
+                style(&existing.bundle.domain).yellow().bold()
+            );
+            eprintln!(
+                "{}",
+                style("If it was copied from another project, recreate it with --force to get a new id")
+                    .yellow()
+            );
+        }
+
         bail!("launch config already present, use --force if you want to recreate it!");
     }
 
-    let config = LaunchConfig::new(options)?;
+    if options.root.is_none() {
+        options.root = detect_build_root(&project_root);
+
+        match &options.root {
+            Some(root) => println!(
+                "{} {}",
+                style("Detected build root").dim(),
+                style(root.display()).bold()
+            ),
+            None => println!(
+                "{}",
+                style("Could not detect a build root, using the project root. Use --root to override.")
+                    .yellow()
+            ),
+        }
+    }
+
+    let redirects_file = options
+        .root
+        .iter()
+        .map(|root| project_root.join(root))
+        .chain([project_root.join("public"), project_root.clone()])
+        .map(|dir| dir.join("_redirects"))
+        .find(|path| path.is_file());
+
+    let mut config = LaunchConfig::new(options)?;
+
+    if let Some(redirects_file) = redirects_file {
+        config.bundle.redirects = parse_redirects(&std::fs::read_to_string(&redirects_file)?);
+
+        println!(
+            "{} {} {}",
+            style("Imported").dim(),
+            style(config.bundle.redirects.len()).bold(),
+            style(format!("redirects from {}", redirects_file.display())).dim()
+        );
+    }
+
     let mut file = File::create(path)?;
     serde_json::to_writer_pretty(&mut file, &config)?;
 
+    if let Some(provider) = print_ci {
+        print!("\n{}", ci::job(provider, &config));
+    }
+
     Ok(())
 }
 
-fn list(endpoint: &str) -> Result<()> {
+/// Reads redirects in the `_redirects` format of other static hosts: `from to [status]` per line
+///
+/// Rules which do not redirect, like rewrites with a 200 status, have no equivalent and are skipped.
+fn parse_redirects(contents: &str) -> Vec<Redirect> {
+    contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let from = parts.next()?;
+            let to = parts.next()?;
+            let status = match parts.next() {
+                Some(status) => status.trim_end_matches('!').parse().ok()?,
+                None => 301,
+            };
+
+            if !(300..400).contains(&status) {
+                eprintln!(
+                    "{}",
+                    style(format!(
+                        "Skipping redirect from {from}, status {status} is not supported"
+                    ))
+                    .yellow()
+                );
+                return None;
+            }
+
+            Some(Redirect {
+                from: from.into(),
+                to: to.into(),
+                status,
+            })
+        })
+        .collect()
+}
+
+/// Guesses the build output directory from common framework conventions
+fn detect_build_root(project_root: &Path) -> Option<PathBuf> {
+    let package = std::fs::read_to_string(project_root.join("package.json"))
+        .ok()
+        .and_then(|package| serde_json::from_str::<serde_json::Value>(&package).ok());
+
+    let build_script = package
+        .as_ref()
+        .and_then(|package| package["scripts"]["build"].as_str());
+
+    let hinted = build_script.and_then(|script| {
+        [
+            ("react-scripts", "build"),
+            ("next", "out"),
+            ("vite", "dist"),
+            ("vue-cli-service", "dist"),
+            ("ng build", "dist"),
+            ("parcel", "dist"),
+        ]
+        .into_iter()
+        .find(|(tool, _)| script.contains(tool))
+        .map(|(_, root)| root)
+    });
+
+    hinted
+        .into_iter()
+        .chain(["dist", "build", "out", "public"])
+        .map(PathBuf::from)
+        .find(|root| project_root.join(root).is_dir())
+        .or_else(|| hinted.map(PathBuf::from))
+}
+
+fn list(
+    endpoint: &str,
+    labels: &[(String, String)],
+    domain: Option<&str>,
+    state: Option<&str>,
+) -> Result<()> {
     let config = load_config();
     let active_id = config.ok().map(|c| c.id);
 
-    let mut bundles = ureq::get(endpoint)
-        .call()
-        .context("http req failed")?
-        .into_json::<HashMap<Ulid, Bundle>>()
-        .context("failed to deserialize response")?
-        .into_iter()
-        .collect::<Vec<_>>();
+    let query = [
+        ("domain", domain),
+        ("state", state),
+        ("prepared", Some("1")),
+    ]
+    .into_iter()
+    .filter_map(|(key, value)| Some((key, value?)))
+    .collect::<Vec<_>>();
+
+    let mut bundles = api::list_stream(endpoint, &query)?
+        .filter(|entry| match entry {
+            Err(_) => true,
+            Ok((_, bundle)) => match bundle {
+                Bundle::Active { config, .. } | Bundle::Prepared { config, .. } => labels
+                    .iter()
+                    .all(|(key, value)| config.labels.get(key) == Some(value)),
+                Bundle::Failed { .. } => labels.is_empty(),
+            },
+        })
+        .collect::<Result<Vec<_>, _>>()?;
 
-    bundles.sort_by_key(|(id, _)| *id);
+    // Prepared versions are listed right below the current one of the same bundle
+    bundles.sort_by_key(|(id, bundle)| (*id, matches!(bundle, Bundle::Prepared { .. })));
 
-    let mut table = Table::new();
+    let mut table = new_table();
 
-    table
-        .load_preset("     ═╪            ")
-        .set_content_arrangement(ContentArrangement::Dynamic)
-        .set_header(vec![
-            Cell::new(""),
-            Cell::new("Name"),
-            Cell::new("Domain").set_alignment(CellAlignment::Center),
-            Cell::new("Size").set_alignment(CellAlignment::Right),
-            Cell::new("Savings").set_alignment(CellAlignment::Right),
-        ]);
+    table.set_header(vec![
+        Cell::new(""),
+        Cell::new(""),
+        Cell::new("Name"),
+        Cell::new("Domain").set_alignment(CellAlignment::Center),
+        Cell::new("Size").set_alignment(CellAlignment::Right),
+        Cell::new("Savings").set_alignment(CellAlignment::Right),
+        Cell::new("Created").set_alignment(CellAlignment::Right),
+    ]);
 
     for (id, bundle) in bundles {
         match bundle {
-            Bundle::Active { config, stats } => {
+            Bundle::Active {
+                config,
+                stats,
+                warning,
+                served,
+            } => {
                 let mut id_cell = Cell::new(id);
 
                 if Some(id) == active_id {
@@ -159,26 +640,79 @@ fn list(endpoint: &str) -> Result<()> {
                     id_cell = id_cell.add_attribute(Attribute::Dim);
                 }
 
-                let brotli = if let Some(compressed) = stats.compressed.get(&Algorithm::Brotli) {
-                    let percentage =
-                        ((stats.compressible - compressed) as f64 / stats.size as f64) * 100.0;
-                    format!("{:0>2.2}%", percentage)
+                // Active bundles which are missing from Caddy's config are not actually live
+                let status = if served {
+                    Cell::new("●").fg(Color::Green)
                 } else {
-                    "100%".into()
+                    Cell::new("○").fg(Color::Yellow)
                 };
 
                 table.add_row(vec![
+                    status,
                     id_cell,
-                    Cell::new(config.name).fg(Color::Green),
-                    Cell::new(config.domain)
-                        .fg(Color::Cyan)
+                    Cell::new(&config.name).fg(Color::Green),
+                    Cell::new(format!(
+                        "{}{}",
+                        config.domain,
+                        config.path_prefix().unwrap_or_default()
+                    ))
+                    .fg(Color::Cyan)
+                    .set_alignment(CellAlignment::Right),
+                    Cell::new(HumanBytes(stats.size)).set_alignment(CellAlignment::Right),
+                    Cell::new(savings(&stats))
+                        .fg(if warning.is_some() {
+                            Color::Yellow
+                        } else {
+                            Color::Reset
+                        })
                         .set_alignment(CellAlignment::Right),
+                    Cell::new(created(id)).set_alignment(CellAlignment::Right),
+                ]);
+
+                if let Some(warning) = warning {
+                    table.add_row(vec![
+                        Cell::new(""),
+                        Cell::new(""),
+                        Cell::new(warning).fg(Color::Yellow),
+                    ]);
+                }
+            }
+            Bundle::Prepared {
+                config,
+                stats,
+                prepared,
+            } => {
+                let age = SystemTime::now()
+                    .duration_since(UNIX_EPOCH + Duration::from_secs(prepared))
+                    .unwrap_or_default();
+
+                table.add_row(vec![
+                    Cell::new("◌").fg(Color::Blue),
+                    Cell::new(id).add_attribute(Attribute::Dim),
+                    Cell::new(format!(
+                        "{} (prepared {} ago)",
+                        config.name,
+                        HumanDuration(age)
+                    ))
+                    .fg(Color::Blue),
+                    Cell::new(format!(
+                        "{}{}",
+                        config.domain,
+                        config.path_prefix().unwrap_or_default()
+                    ))
+                    .fg(Color::Cyan)
+                    .set_alignment(CellAlignment::Right),
                     Cell::new(HumanBytes(stats.size)).set_alignment(CellAlignment::Right),
-                    Cell::new(brotli).set_alignment(CellAlignment::Right),
+                    Cell::new(savings(&stats)).set_alignment(CellAlignment::Right),
+                    Cell::new(created(id)).set_alignment(CellAlignment::Right),
                 ]);
             }
             Bundle::Failed { error } => {
-                table.add_row(vec![id.to_string(), error]);
+                table.add_row(vec![
+                    Cell::new("✗").fg(Color::Red),
+                    Cell::new(id),
+                    Cell::new(error),
+                ]);
             }
         }
     }
@@ -188,69 +722,355 @@ fn list(endpoint: &str) -> Result<()> {
     Ok(())
 }
 
-fn launch(endpoint: &str) -> Result<()> {
-    println!(
-        "{} 🪄  Designing schematics...",
-        style("[1/4]").bold().dim()
-    );
+fn history(endpoint: &str, domain: Option<String>) -> Result<()> {
+    let domain = domain
+        .or_else(|| Some(load_config().ok()?.bundle.domain))
+        .ok_or(anyhow!("could not infer domain"))?;
+
+    let history = api::history(endpoint, &domain)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let mut table = new_table();
+
+    table.set_header(vec![
+        Cell::new("Launched"),
+        Cell::new(""),
+        Cell::new("Size").set_alignment(CellAlignment::Right),
+        Cell::new("Savings").set_alignment(CellAlignment::Right),
+    ]);
+
+    for entry in history.into_iter().rev() {
+        let age = Duration::from_secs(now.saturating_sub(entry.timestamp));
+
+        table.add_row(vec![
+            Cell::new(format!("{} ago", HumanDuration(age))),
+            Cell::new(entry.id).add_attribute(Attribute::Dim),
+            Cell::new(HumanBytes(entry.stats.size)).set_alignment(CellAlignment::Right),
+            Cell::new(savings(&entry.stats)).set_alignment(CellAlignment::Right),
+        ]);
+    }
+
+    println!("\n{table}\n");
+
+    Ok(())
+}
+
+/// Age of a deployment id, which embeds the time `launch init` generated it
+fn created(id: Ulid) -> String {
+    let age = SystemTime::now()
+        .duration_since(id.datetime())
+        .unwrap_or_default();
+
+    format!("{} ago", HumanDuration(age))
+}
+
+/// Share of the total bundle size saved by brotli precompression
+fn savings(stats: &Statistics) -> String {
+    if let Some(compressed) = stats.compressed.get(&Algorithm::Brotli) {
+        let percentage = ((stats.compressible - compressed) as f64 / stats.size as f64) * 100.0;
+        format!("{:0>2.2}%", percentage)
+    } else {
+        "100%".into()
+    }
+}
+
+fn launch(options: LaunchOptions) -> Result<()> {
+    let endpoint = options.endpoint.as_str();
+    let output = if options.porcelain {
+        Output::Porcelain
+    } else if interactive() {
+        Output::Pretty
+    } else {
+        Output::Plain
+    };
+    let porcelain = output == Output::Porcelain;
+
+    if porcelain {
+        eprintln!("phase=design");
+    } else {
+        println!(
+            "{} 🪄  Designing schematics...",
+            style("[1/4]").bold().dim()
+        );
+    }
+
+    let mut config = load_config().context("failed to find load config")?;
+
+    // Overrides only apply to this upload, the launch config on disk stays as it is
+    if let Some(domain) = &options.domain {
+        config.bundle.domain = domain.clone();
+    }
+
+    if let Some(name) = &options.name {
+        config.bundle.name = name.clone();
+    }
+
+    if let Some(id) = options.id {
+        config.id = id;
+    }
+
+    config.bundle.validate().map_err(api::ClientError::Config)?;
+
+    // Servers predating the version endpoint are not worth a warning
+    if let Ok(server) = api::version(endpoint) {
+        let client = env!("CARGO_PKG_VERSION");
+
+        if !server.compatible(client) {
+            if porcelain {
+                eprintln!("warning=version server={} client={client}", server.version);
+            } else {
+                println!(
+                    "         {}",
+                    style(format!(
+                        "⚠️  Ground control runs version {} while this rocket is {client}",
+                        server.version
+                    ))
+                    .yellow()
+                    .bold()
+                );
+            }
+        }
+    }
+
+    if let Some(command) = options.build.as_ref().or(config.build.as_ref()) {
+        if porcelain {
+            eprintln!("phase=build");
+        } else {
+            println!(
+                "         {} {}",
+                style("Building with").dim(),
+                style(command).dim().bold()
+            );
+        }
+
+        run_build(command)?;
+    }
 
-    let config = load_config().context("failed to find load config")?;
     let root = find_build_root(&config).context("failed to find build root")?;
 
+    if !root.is_dir() {
+        bail!(
+            "build root {} does not exist, did you forget to build? (check `root` in {LAUNCH_FILE_NAME})",
+            root.display()
+        );
+    }
+
+    let empty = !walkdir::WalkDir::new(&root)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .any(|entry| entry.file_type().is_file());
+
+    if empty && !options.allow_empty {
+        bail!(
+            "build root {} contains no files, did you forget to build? (check `root` in {LAUNCH_FILE_NAME} or pass --allow-empty)",
+            root.display()
+        );
+    }
+
+    if !options.yes && same_dir(&root, &find_project_root()?) {
+        confirm_project_root(&root, porcelain)?;
+    }
+
     let temp = temp_dir::TempDir::new().context("failed to create temp dir")?;
-    let path = temp.child("launch.bundle.tar");
-    let path_meta = temp.child("launch.config");
 
-    std::fs::write(&path_meta, serde_json::to_string(&config.bundle)?)
-        .context("failed to write metadata")?;
+    if porcelain {
+        eprintln!("phase=assemble");
+    } else {
+        println!("{} 🛠️  Assembling rocket...", style("[2/4]").bold().dim());
+    }
 
-    println!("{} 🛠️  Assembling rocket...", style("[2/4]").bold().dim());
+    let (mut file, skipped) = api::bundle(
+        &config.bundle,
+        &root,
+        &temp.child("launch.bundle.tar"),
+        options.follow_symlinks,
+    )?;
+
+    for link in skipped {
+        let path = link.path.strip_prefix(&root).unwrap_or(&link.path);
+
+        if porcelain {
+            eprintln!(
+                "warning=symlink path={} reason={}",
+                path.display(),
+                link.reason.name()
+            );
+        } else {
+            println!(
+                "         {}",
+                style(format!(
+                    "⚠️  Left {} behind, the symlink {}",
+                    path.display(),
+                    link.reason
+                ))
+                .yellow()
+                .bold()
+            );
+        }
+    }
 
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .truncate(true)
-        .create(true)
-        .open(path)
-        .context("failed to create archive file")?;
+    if porcelain {
+        eprintln!("phase=assemble size={}", file.metadata()?.len());
+    } else {
+        println!(
+            "         {} {}",
+            style("Takeoff mass is").dim(),
+            style(HumanBytes(file.metadata()?.len())).dim().bold(),
+        );
+    }
 
-    {
-        let mut buf_wrt = BufWriter::new(&mut file);
-        let mut builder = tar::Builder::new(&mut buf_wrt);
+    if let Some(max_size) = &config.max_size {
+        let budget = parse_size(max_size)
+            .context(ExitCode::Config)
+            .context("invalid max_size in launch config")?;
+        let size = file.metadata()?.len();
+
+        if size > budget {
+            let message = format!(
+                "Bundle exceeds its size budget of {} by {}",
+                HumanBytes(budget),
+                HumanBytes(size - budget)
+            );
 
-        builder
-            .append_path_with_name(path_meta, "./launch.config")
-            .context("failed to add launch config to archive")?;
+            if options.strict_budget {
+                bail!(message);
+            }
 
-        builder
-            .append_dir_all(".", root)
-            .context("failed to add files to archive")?;
+            if porcelain {
+                eprintln!("warning=budget size={size} budget={budget}");
+            } else {
+                println!(
+                    "         {}",
+                    style(format!("⚠️  {message}")).yellow().bold()
+                );
+            }
+        }
+    }
 
-        builder.finish().context("failed to finalise archive")?;
+    if !porcelain {
+        println!(
+            "{} ⏰ Starting final countdown...",
+            style("[3/4]").bold().dim()
+        );
     }
 
-    file.seek(SeekFrom::Start(0))
-        .context("failed to seek through archive")?;
+    let gzip = options.gzip && api::accepts_gzip(endpoint)?;
 
-    println!(
-        "         {} {}",
-        style("Takeoff mass is").dim(),
-        style(HumanBytes(file.metadata()?.len())).dim().bold(),
-    );
+    if gzip {
+        file = api::gzip(&mut file, &temp.child("launch.bundle.tar.gz"))?;
+    }
 
-    println!(
-        "{} ⏰ Starting final countdown...",
-        style("[3/4]").bold().dim()
-    );
+    let size = file.metadata()?.len();
 
-    let mut reader = CountingReader::new(&mut file)?;
-    let req_path = format!("{endpoint}/bundle/{}", config.id);
-    let res = ureq::post(&req_path).send(&mut reader);
-    reader.finish();
+    if gzip {
+        if porcelain {
+            eprintln!("phase=gzip size={size}");
+        } else {
+            println!(
+                "         {} {}",
+                style("Folded for transport to").dim(),
+                style(HumanBytes(size)).dim().bold(),
+            );
+        }
+    }
+
+    let mut upload_options = api::UploadOptions {
+        ttl: options.ttl,
+        idempotency_key: Some(api::idempotency_key()),
+        force: options.force,
+        overwrite: options.overwrite,
+        verbose: options.verbose,
+        gzip,
+        prepare: options.prepare,
+    };
+
+    let res = loop {
+        let mut reader = CountingReader::new(&mut file, output)?;
+        let res = api::upload(endpoint, config.id, &mut reader, size, &upload_options);
+        reader.finish();
+
+        let change = match &res {
+            Err(api::ClientError::Status {
+                code: 409, body, ..
+            }) if !upload_options.overwrite => serde_json::from_str::<DomainChange>(body).ok(),
+            _ => None,
+        };
+
+        match change {
+            Some(change) if confirm_overwrite(&change, porcelain)? => {
+                upload_options.overwrite = true;
+                upload_options.idempotency_key = Some(api::idempotency_key());
+                file.seek(SeekFrom::Start(0))?;
+            }
+            _ => break res,
+        }
+    };
+
+    if let Ok(Deployment {
+        route: Some(route), ..
+    }) = &res
+    {
+        let route = serde_json::to_string_pretty(route)?;
+
+        if porcelain {
+            eprintln!("{route}");
+        } else {
+            println!("         {}\n{route}", style("Flight plan:").dim());
+        }
+    }
 
     match res {
-        Ok(response) => {
-            let stats: Statistics = serde_json::from_reader(response.into_reader())?;
+        Ok(Deployment { stats, .. }) if options.prepare => {
+            if porcelain {
+                eprintln!("phase=prepared size={}", stats.size);
+            } else {
+                println!(
+                    "Rocket is fueled and waiting on the pad, launch it with {}",
+                    style("launch activate").bold()
+                );
+            }
+
+            Ok(())
+        }
+        Ok(Deployment {
+            urls,
+            stats,
+            replaced,
+            ..
+        }) if porcelain => {
+            for id in replaced {
+                eprintln!("replaced={id}");
+            }
+
+            eprintln!(
+                "phase=done size={} savings={} urls={}",
+                stats.size,
+                savings(&stats),
+                urls.join(",")
+            );
+
+            if let (true, Some(url)) = (options.open, urls.first()) {
+                open_browser(url);
+            }
+
+            Ok(())
+        }
+        Ok(Deployment {
+            urls,
+            stats,
+            replaced,
+            ..
+        }) => {
+            for id in replaced {
+                println!(
+                    "         {} {}",
+                    style("Replaced deployment").dim(),
+                    style(id).dim().bold()
+                );
+            }
 
             if let Some(compressed) = stats.compressed.get(&Algorithm::Brotli) {
                 let percentage_total =
@@ -275,29 +1095,252 @@ fn launch(endpoint: &str) -> Result<()> {
                         .bold(),
                     style("% of total mass").dim()
                 );
+
+                if stats.profile != CompressionProfile::Best {
+                    println!(
+                        "         {} {} {}",
+                        style("Throttled to the").dim(),
+                        style(stats.profile.name()).dim().bold(),
+                        style("profile").dim()
+                    );
+                }
             }
 
-            println!("{}", include_str!("./liftoff.txt"));
+            match output {
+                Output::Pretty => println!("{}", include_str!("./liftoff.txt")),
+                _ => println!("Orbital insertion complete!"),
+            }
 
-            let url = format!("https://{}", config.bundle.domain);
-            println!(
-                "Visit \x1b]8;;{}\x07{}\x1b]8;;\x07 to check the mission!",
-                url, url
-            );
+            for url in urls.iter() {
+                println!("Visit {} to check the mission!", link(url));
+            }
+
+            if let (true, Some(url)) = (options.open, urls.first()) {
+                open_browser(url);
+            }
 
             Ok(())
         }
-        Err(ureq::Error::Status(code, response)) => Err(anyhow!(
-            "Uh, oh ... we had a rapid, unscheduled disassembly 😳\n\t({} — {})",
-            code,
-            response.into_string().unwrap_or_default()
-        )),
+        Err(api::ClientError::Status { code, body, .. }) => {
+            let conflict = serde_json::from_str::<DomainConflict>(&body);
+            let change = serde_json::from_str::<DomainChange>(&body);
+
+            let message = match (code, conflict, change) {
+                (409, Ok(conflict), _) => format!(
+                    "Domain {} is already served by deployment {} 🛰️\n\tDeorbit it first (launch deorbit {}) or use a different domain",
+                    conflict.existing, conflict.id, conflict.id
+                ),
+                (409, _, Ok(change)) => format!(
+                    "Deployment {} serves {}, not {} 🛰️\n\tPass --overwrite to move it, or run launch init --force to get a new id if the config was copied",
+                    change.id, change.served, change.uploaded
+                ),
+                (409, _, _) => format!("The launch pad is occupied 🛰️\n\t({body})"),
+                (502, ..) => format!(
+                    "The payload reached orbit but ground control could not update the routing 📡\n\t({body})"
+                ),
+                _ => format!(
+                    "Uh, oh ... we had a rapid, unscheduled disassembly 😳\n\t({code} — {body})"
+                ),
+            };
+
+            Err(anyhow::Error::new(ExitCode::from_status(code)).context(message))
+        }
         Err(error) => Err(error).context("failed to send request"),
     }
 
     // TODO Verify deployment
 }
 
+fn same_dir(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Makes sure a build root covering the whole project, `.git` and all, is deployed on purpose
+fn confirm_project_root(root: &Path, porcelain: bool) -> Result<()> {
+    let (files, size) = walkdir::WalkDir::new(root)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .fold((0, 0), |(files, size), entry| {
+            let len = entry.metadata().map(|m| m.len()).unwrap_or_default();
+            (files + 1, size + len)
+        });
+
+    let message = format!(
+        "The build root is the project root, {files} files weighing {} would be deployed",
+        HumanBytes(size)
+    );
+
+    if porcelain {
+        eprintln!("warning=project_root files={files} size={size}");
+    } else {
+        println!(
+            "         {}",
+            style(format!("⚠️  {message}")).yellow().bold()
+        );
+    }
+
+    let term = console::Term::stderr();
+
+    if porcelain || !term.is_term() {
+        return Err(anyhow::Error::new(ExitCode::Config).context(format!(
+            "{message}, set `root` in {LAUNCH_FILE_NAME} or pass --yes"
+        )));
+    }
+
+    term.write_str("         Launch the whole project anyway? [y/N] ")?;
+
+    match term.read_line()?.trim() {
+        "y" | "Y" | "yes" => Ok(()),
+        _ => Err(anyhow::Error::new(ExitCode::Config).context("launch aborted")),
+    }
+}
+
+/// Asks whether an upload may move its deployment to another domain, `false` if declined
+///
+/// Without a terminal to ask on, the upload fails with the server's answer instead.
+fn confirm_overwrite(change: &DomainChange, porcelain: bool) -> Result<bool> {
+    let term = console::Term::stderr();
+
+    if porcelain || !term.is_term() {
+        return Ok(false);
+    }
+
+    term.write_str(&format!(
+        "         Deployment {} serves {}, move it to {}? [y/N] ",
+        change.id, change.served, change.uploaded
+    ))?;
+
+    Ok(matches!(term.read_line()?.trim(), "y" | "Y" | "yes"))
+}
+
+/// Runs the build command through the shell in the project root, inheriting output and env
+fn run_build(command: &str) -> Result<()> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = std::process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = std::process::Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+
+    let status = shell
+        .arg(command)
+        .current_dir(find_project_root()?)
+        .status()
+        .with_context(|| format!("failed to run build command '{command}'"))?;
+
+    if !status.success() {
+        bail!("build command '{command}' failed ({status}), aborting launch");
+    }
+
+    Ok(())
+}
+
+/// Parses human readable sizes like `10MB` or `1.5 GiB`, using binary units like [`HumanBytes`]
+fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(size.len());
+    let (value, unit) = size.split_at(split);
+
+    let value: f64 = value
+        .parse()
+        .with_context(|| format!("invalid size '{size}'"))?;
+
+    let exponent = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 0,
+        "k" | "kb" | "kib" => 1,
+        "m" | "mb" | "mib" => 2,
+        "g" | "gb" | "gib" => 3,
+        "t" | "tb" | "tib" => 4,
+        unit => bail!("unknown size unit '{unit}'"),
+    };
+
+    Ok((value * 1024f64.powi(exponent)) as u64)
+}
+
+/// Parses a `key=value` label filter
+fn parse_label(label: &str) -> Result<(String, String)> {
+    let (key, value) = label
+        .split_once('=')
+        .with_context(|| format!("invalid label '{label}', expected key=value"))?;
+
+    Ok((key.trim().to_owned(), value.trim().to_owned()))
+}
+
+/// Parses durations like `90s`, `30m`, `24h` or `7d`, defaulting to seconds without a unit
+fn parse_duration(duration: &str) -> Result<Duration> {
+    let duration = duration.trim();
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (value, unit) = duration.split_at(split);
+
+    let value: u64 = value
+        .parse()
+        .with_context(|| format!("invalid duration '{duration}'"))?;
+
+    let factor = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        unit => bail!("unknown duration unit '{unit}'"),
+    };
+
+    Ok(Duration::from_secs(value * factor))
+}
+
+fn open_browser(url: &str) {
+    let headless = std::env::var_os("CI").is_some()
+        || (cfg!(target_os = "linux")
+            && std::env::var_os("DISPLAY").is_none()
+            && std::env::var_os("WAYLAND_DISPLAY").is_none());
+
+    if headless {
+        eprintln!(
+            "{}",
+            style("No browser available, skipping --open").yellow()
+        );
+        return;
+    }
+
+    let mut command = if cfg!(target_os = "macos") {
+        std::process::Command::new("open")
+    } else if cfg!(target_os = "windows") {
+        let mut command = std::process::Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    } else {
+        std::process::Command::new("xdg-open")
+    };
+
+    browser_url(&mut command, url);
+
+    if let Err(e) = command.spawn() {
+        eprintln!("{} {}", style("Failed to open browser:").yellow(), e);
+    }
+}
+
+/// Quotes the URL for `start`, as cmd would otherwise split it at characters like `&`
+#[cfg(windows)]
+fn browser_url(command: &mut std::process::Command, url: &str) {
+    use std::os::windows::process::CommandExt;
+    command.raw_arg(format!("\"{url}\""));
+}
+
+#[cfg(not(windows))]
+fn browser_url(command: &mut std::process::Command, url: &str) {
+    command.arg(url);
+}
+
 fn delete(endpoint: &str, id: Option<Ulid>) -> Result<()> {
     let id = id
         .or_else(|| {
@@ -306,45 +1349,404 @@ fn delete(endpoint: &str, id: Option<Ulid>) -> Result<()> {
         })
         .ok_or(anyhow!("could not infer deployment id"))?;
 
-    ureq::delete(&format!("{endpoint}/bundle/{}", id))
-        .call()
-        .context("failed to delete deployment")?;
+    Ok(api::delete(endpoint, id)?)
+}
+
+fn activate(endpoint: &str, id: Option<Ulid>, force: bool) -> Result<()> {
+    let id = id
+        .or_else(|| {
+            let config = load_config().ok()?;
+            Some(config.id)
+        })
+        .ok_or(anyhow!("could not infer deployment id"))?;
+
+    let deployment = api::activate(endpoint, id, force)?;
+
+    for replaced in deployment.replaced {
+        println!("Replaced deployment {}", style(replaced).bold());
+    }
+
+    for url in deployment.urls.iter() {
+        println!("Visit {} to check the mission!", link(url));
+    }
+
+    Ok(())
+}
+
+fn rename(endpoint: &str, id: Ulid, name: &str) -> Result<()> {
+    let config = api::update(endpoint, id, &serde_json::json!({ "name": name }))?;
+
+    println!(
+        "Rechristened {} as {}",
+        style(id).bold(),
+        style(&config.name).green()
+    );
+
+    Ok(())
+}
+
+fn download(endpoint: &str, id: Option<Ulid>, output: &Path) -> Result<()> {
+    let id = id
+        .or_else(|| {
+            let config = load_config().ok()?;
+            Some(config.id)
+        })
+        .ok_or(anyhow!("could not infer deployment id"))?;
+
+    let size = api::download(endpoint, id, output)?;
+
+    println!(
+        "Recovered {} of {} into {}",
+        HumanBytes(size),
+        style(id).bold(),
+        output.display()
+    );
+
+    Ok(())
+}
+
+fn files(endpoint: &str, id: Option<Ulid>, worst: bool) -> Result<()> {
+    let id = match id {
+        Some(id) => id,
+        None => load_config().context("failed to find load config")?.id,
+    };
+
+    let mut files = api::files(endpoint, id)?;
+
+    // Files without sidecars are listed last, as there is nothing to tune about them
+    if worst {
+        files.sort_by(|a, b| match (a.savings(), b.savings()) {
+            (Some(a), Some(b)) => a.total_cmp(&b),
+            (a, b) => b.is_some().cmp(&a.is_some()),
+        });
+    }
+
+    let mut table = new_table();
+
+    table.set_header(vec![
+        Cell::new("Path"),
+        Cell::new("Size").set_alignment(CellAlignment::Right),
+        Cell::new("Brotli").set_alignment(CellAlignment::Right),
+        Cell::new("Gzip").set_alignment(CellAlignment::Right),
+        Cell::new("Savings").set_alignment(CellAlignment::Right),
+    ]);
+
+    let sidecar = |entry: &ManifestEntry, algorithm| match entry.compressed.get(&algorithm) {
+        Some(size) => Cell::new(HumanBytes(*size)),
+        None => Cell::new("-").add_attribute(Attribute::Dim),
+    };
+
+    for entry in files.iter() {
+        let savings = match entry.savings() {
+            Some(savings) => Cell::new(format!("{:.2}%", savings * 100.0)),
+            None => Cell::new("-").add_attribute(Attribute::Dim),
+        };
+
+        table.add_row(vec![
+            Cell::new(&entry.path),
+            Cell::new(HumanBytes(entry.size)).set_alignment(CellAlignment::Right),
+            sidecar(entry, Algorithm::Brotli).set_alignment(CellAlignment::Right),
+            sidecar(entry, Algorithm::Gzip).set_alignment(CellAlignment::Right),
+            savings.set_alignment(CellAlignment::Right),
+        ]);
+    }
+
+    println!("\n{table}\n");
+
+    Ok(())
+}
+
+fn diff(endpoint: &str, id: Option<Ulid>, follow_symlinks: bool) -> Result<()> {
+    let config = load_config().context("failed to find load config")?;
+    let root = find_build_root(&config).context("failed to find build root")?;
+    let id = id.unwrap_or(config.id);
+
+    let temp = temp_dir::TempDir::new().context("failed to create temp dir")?;
+    let path = temp.child("launch.bundle.tar");
+    api::download(endpoint, id, &path)?;
+
+    diff::compare(File::open(&path)?, &root, follow_symlinks)
+}
+
+fn logs(endpoint: &str, id: Option<Ulid>, follow: bool) -> Result<()> {
+    let id = id
+        .or_else(|| Some(load_config().ok()?.id))
+        .ok_or(anyhow!("could not infer deployment id"))?;
+
+    let mut since = 0;
+
+    loop {
+        for entry in api::logs(endpoint, id, since)? {
+            let time = UNIX_EPOCH + Duration::from_secs(entry.timestamp);
+            let age = SystemTime::now().duration_since(time).unwrap_or_default();
+
+            println!(
+                "{} {}",
+                style(format!("{:>16}", format!("{} ago", HumanDuration(age)))).dim(),
+                entry.message
+            );
+
+            since = entry.sequence + 1;
+        }
+
+        if !follow {
+            return Ok(());
+        }
+
+        std::thread::sleep(Duration::from_secs(2));
+    }
+}
+
+fn prune(endpoint: &str) -> Result<()> {
+    let removed = api::prune(endpoint)?;
+
+    println!("Cleared {removed} debris from orbit");
+
+    Ok(())
+}
+
+fn check() -> Result<()> {
+    let config = load_config()?;
+    let path = config_path()?;
+    println!("{} {} parses", style("✓").green(), path.display());
+
+    config.bundle.validate().map_err(api::ClientError::Config)?;
+    println!("{} bundle config is valid", style("✓").green());
+
+    if let Some(max_size) = &config.max_size {
+        parse_size(max_size)
+            .context(ExitCode::Config)
+            .context("invalid max_size in launch config")?;
+        println!("{} size budget of {max_size}", style("✓").green());
+    }
+
+    let root = find_build_root(&config).context("failed to find build root")?;
+
+    if !root.is_dir() {
+        return Err(anyhow::Error::new(ExitCode::Config).context(format!(
+            "build root {} does not exist (check `root` in {LAUNCH_FILE_NAME})",
+            root.display()
+        )));
+    }
+
+    let files = walkdir::WalkDir::new(&root)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .count();
+
+    if files == 0 {
+        return Err(anyhow::Error::new(ExitCode::Config).context(format!(
+            "build root {} contains no files (did the build run?)",
+            root.display()
+        )));
+    }
+
+    println!(
+        "{} build root {} contains {files} files",
+        style("✓").green(),
+        root.display()
+    );
+
+    if same_dir(&root, &find_project_root()?) {
+        println!(
+            "{} build root is the project root, launching will ask for confirmation",
+            style("!").yellow()
+        );
+    }
+
+    Ok(())
+}
+
+fn maintenance(endpoint: &str, state: Option<Switch>) -> Result<()> {
+    let enabled = match state {
+        Some(state) => api::set_maintenance(endpoint, state == Switch::On)?,
+        None => api::maintenance(endpoint)?,
+    };
+
+    if enabled {
+        println!("Ground control is {}", style("in maintenance").yellow());
+    } else {
+        println!("Ground control is {}", style("operational").green());
+    }
 
     Ok(())
 }
 
 fn load_config() -> Result<LaunchConfig> {
-    let path = find_project_root()?.join(LAUNCH_FILE_NAME);
-    let file = File::open(path)?;
-    let config: LaunchConfig = serde_json::from_reader(&file)?;
-    Ok(config)
+    let load = || -> Result<LaunchConfig> {
+        let path = config_path()?;
+        let file =
+            File::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+        let mut value: serde_json::Value = serde_json::from_reader(&file)?;
+        expand_config_env(&mut value)?;
+        let config: LaunchConfig = serde_json::from_value(value)?;
+        Ok(config)
+    };
+
+    load().context(ExitCode::Config)
+}
+
+/// Expands `${VAR}` and `${VAR:-default}` in the string values of the bundle config
+///
+/// Fields only read by the client, like the `id` and `root`, are left as they are.
+fn expand_config_env(config: &mut serde_json::Value) -> Result<()> {
+    const CLIENT_FIELDS: [&str; 4] = ["id", "root", "max_size", "build"];
+
+    if let serde_json::Value::Object(map) = config {
+        map.iter_mut()
+            .filter(|(key, _)| !CLIENT_FIELDS.contains(&key.as_str()))
+            .try_for_each(|(_, value)| expand_env(value))?;
+    }
+
+    Ok(())
+}
+
+fn expand_env(value: &mut serde_json::Value) -> Result<()> {
+    use serde_json::Value;
+
+    match value {
+        Value::String(string) if string.contains("${") => *string = expand_str(string)?,
+        Value::Array(values) => values.iter_mut().try_for_each(expand_env)?,
+        Value::Object(map) => map.values_mut().try_for_each(expand_env)?,
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn expand_str(input: &str) -> Result<String> {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(start) = rest.find("${") {
+        output.push_str(&rest[..start]);
+
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("unterminated variable reference in '{input}'"))?;
+        let expression = &rest[start + 2..start + end];
+
+        let (name, default) = match expression.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expression, None),
+        };
+
+        // Like in shells, empty variables fall back to the default as well
+        match (std::env::var(name), default) {
+            (Ok(value), Some(default)) if value.is_empty() => output.push_str(default),
+            (Ok(value), _) => output.push_str(&value),
+            (Err(_), Some(default)) => output.push_str(default),
+            (Err(_), None) => {
+                bail!("environment variable {name} referenced in launch.json is not set")
+            }
+        }
+
+        rest = &rest[start + end + 1..];
+    }
+
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Location of the launch config, either the one passed with `--config` or the default one
+fn config_path() -> Result<PathBuf> {
+    match CONFIG_PATH.get() {
+        Some(path) => Ok(path.clone()),
+        None => Ok(find_project_root()?.join(LAUNCH_FILE_NAME)),
+    }
 }
 
 fn find_build_root(config: &LaunchConfig) -> Result<PathBuf> {
     Ok(find_project_root()?.join(&config.root))
 }
 
+/// Locates the directory holding the launch config
+///
+/// In order of precedence, this is the root of the enclosing git repository, the closest
+/// ancestor containing a `launch.json`, or the current directory.
 fn find_project_root() -> Result<PathBuf> {
     let cwd = current_dir()?;
-    let repo = Repository::open_ext::<_, PathBuf, _>(cwd, RepositoryOpenFlags::empty(), vec![])?;
 
-    Ok(repo
-        .path()
-        .parent()
-        .ok_or_else(|| anyhow!("git repo has no parent directory"))?
+    if let Ok(repo) =
+        Repository::open_ext::<_, PathBuf, _>(&cwd, RepositoryOpenFlags::empty(), vec![])
+    {
+        if let Some(root) = repo.workdir().or_else(|| repo.path().parent()) {
+            return Ok(root.to_path_buf());
+        }
+    }
+
+    Ok(cwd
+        .ancestors()
+        .find(|dir| dir.join(LAUNCH_FILE_NAME).is_file())
+        .unwrap_or(&cwd)
         .to_path_buf())
 }
 
+/// How progress is reported while launching
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Output {
+    /// Colors, progress bars and ASCII art for interactive terminals
+    Pretty,
+    /// Line based output without escape sequences, for logs and `NO_COLOR`
+    Plain,
+    /// `key=value` lines on stderr for other tools
+    Porcelain,
+}
+
+fn new_table() -> Table {
+    let mut table = Table::new();
+
+    table
+        .load_preset("     ═╪            ")
+        .set_content_arrangement(ContentArrangement::Dynamic);
+
+    if std::env::var_os("NO_COLOR").is_some() {
+        table.force_no_tty();
+    }
+
+    table
+}
+
+/// Whether stdout is a terminal which should receive escape sequences
+fn interactive() -> bool {
+    console::Term::stdout().is_term() && std::env::var_os("NO_COLOR").is_none()
+}
+
+/// Renders a clickable OSC 8 hyperlink on interactive terminals and the bare URL otherwise
+fn link(url: &str) -> String {
+    if interactive() {
+        format!("\x1b]8;;{url}\x07{url}\x1b]8;;\x07")
+    } else {
+        url.to_owned()
+    }
+}
+
 struct CountingReader<'f> {
     file: &'f mut File,
     bar: ProgressBar,
     read_finished: bool,
+    /// Reports throttled lines instead of drawing the bar unless the output is pretty
+    output: Output,
+    last_report: Instant,
+    started: Instant,
+    /// Time it took to send the whole archive, set once the last byte was read
+    transfer: Option<Duration>,
 }
 
 impl<'f> CountingReader<'f> {
-    fn new(file: &'f mut File) -> Result<Self> {
+    /// Minimum time between two porcelain upload reports
+    const REPORT_INTERVAL: Duration = Duration::from_millis(250);
+    /// Minimum time between two plain upload reports, which end up in logs read by humans
+    const PLAIN_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+    fn new(file: &'f mut File, output: Output) -> Result<Self> {
         let bar = ProgressBar::new(file.metadata()?.len());
 
+        if output != Output::Pretty {
+            bar.set_draw_target(ProgressDrawTarget::hidden());
+        }
+
         bar.set_style(
             ProgressStyle::with_template(
                 "\n{spinner:.green} [{smoothed_eta}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes}",
@@ -370,11 +1772,41 @@ impl<'f> CountingReader<'f> {
             bar,
             file,
             read_finished: false,
+            output,
+            last_report: Instant::now(),
+            started: Instant::now(),
+            transfer: None,
         })
     }
 
+    fn report(&mut self) {
+        self.last_report = Instant::now();
+
+        match self.output {
+            Output::Porcelain => eprintln!(
+                "phase=upload bytes={}/{} elapsed_ms={}",
+                self.bar.position(),
+                self.bar.length().unwrap_or_default(),
+                self.bar.elapsed().as_millis()
+            ),
+            _ => println!(
+                "         Uploaded {} of {}",
+                HumanBytes(self.bar.position()),
+                HumanBytes(self.bar.length().unwrap_or_default())
+            ),
+        }
+    }
+
     fn close_read(&mut self) {
         self.read_finished = true;
+        self.transfer = Some(self.started.elapsed());
+
+        if self.output == Output::Porcelain {
+            self.report();
+            eprintln!("phase=deploy");
+            return;
+        }
+
         self.bar.finish_and_clear();
 
         println!(
@@ -383,6 +1815,10 @@ impl<'f> CountingReader<'f> {
             style(HumanDuration(self.bar.elapsed())).dim().bold(),
         );
 
+        if self.output == Output::Plain {
+            return;
+        }
+
         self.bar = ProgressBar::new_spinner();
         self.bar.enable_steady_tick(Duration::from_millis(50));
         self.bar.set_style(
@@ -395,8 +1831,37 @@ impl<'f> CountingReader<'f> {
     }
 
     fn finish(&self) {
+        let total = self.started.elapsed();
+        let size = self.bar.length().unwrap_or_default();
+        let transfer = self.transfer.unwrap_or(total);
+        let rate = (size as f64 / transfer.as_secs_f64().max(0.001)) as u64;
+
+        if self.output == Output::Porcelain {
+            eprintln!(
+                "phase=transfer bytes={size} elapsed_ms={} total_ms={} bytes_per_sec={rate}",
+                transfer.as_millis(),
+                total.as_millis()
+            );
+            return;
+        }
+
         self.bar.finish_and_clear();
         println!("{} 🚀 Main engine ignition...", style("[4/4]").bold().dim());
+        println!(
+            "         {} {}{} {}",
+            style("Climbed at").dim(),
+            style(HumanBytes(rate)).dim().bold(),
+            style("/s, ground control answered after").dim(),
+            style(HumanDuration(total)).dim().bold(),
+        );
+    }
+}
+
+impl<'f> Seek for CountingReader<'f> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = self.file.seek(pos)?;
+        self.bar.set_position(position);
+        Ok(position)
     }
 }
 
@@ -405,6 +1870,18 @@ impl<'f> Read for CountingReader<'f> {
         let read = self.file.read(buf)?;
         self.bar.inc(read as u64);
 
+        let interval = match self.output {
+            Output::Pretty => None,
+            Output::Plain => Some(Self::PLAIN_REPORT_INTERVAL),
+            Output::Porcelain => Some(Self::REPORT_INTERVAL),
+        };
+
+        if let (Some(interval), false) = (interval, self.read_finished) {
+            if self.last_report.elapsed() >= interval {
+                self.report();
+            }
+        }
+
         if !self.read_finished && self.bar.position() == self.bar.length().unwrap() {
             self.close_read();
         }
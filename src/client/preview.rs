@@ -0,0 +1,186 @@
+use super::link;
+use crate::server::{Algorithm, Compressor};
+use crate::shared::BundleConfig;
+use anyhow::{Context, Result};
+use console::style;
+use std::fs::{self, File};
+use std::path::{Component, Path, PathBuf};
+use tiny_http::{Header, Request, Response, ResponseBox, Server};
+use walkdir::WalkDir;
+
+/// Serves the build root locally, mimicking the precompression and fallback of a deployment
+pub fn serve(config: &BundleConfig, root: &Path) -> Result<()> {
+    let temp =
+        temp_dir::TempDir::with_prefix("launch-preview-").context("failed to create temp dir")?;
+    copy_dir(root, temp.path()).context("failed to copy build root")?;
+
+    // Originals are kept, as the preview serves them to clients without a matching encoding
+    let compressor = Compressor::default();
+    let unstripped = BundleConfig {
+        strip_originals: false,
+        ..config.clone()
+    };
+    compressor
+        .compress(temp.path(), &unstripped, None)
+        .context("failed to compress files")?;
+
+    let server = Server::http("127.0.0.1:0")
+        .map_err(|e| anyhow::anyhow!(e))
+        .context("failed to bind preview server")?;
+
+    let url = format!("http://{}", server.server_addr());
+    println!(
+        "Previewing {} at {}",
+        style(&config.name).green(),
+        link(&url)
+    );
+
+    for request in server.incoming_requests() {
+        let response = respond(
+            &request,
+            config,
+            temp.path(),
+            &compressor.algorithms_for(config),
+        );
+        request.respond(response).ok();
+    }
+
+    Ok(())
+}
+
+fn respond(
+    request: &Request,
+    config: &BundleConfig,
+    root: &Path,
+    algorithms: &[Algorithm],
+) -> ResponseBox {
+    let path = request.url().split('?').next().unwrap_or_default();
+
+    let (file, status) = match (resolve(path, config, root), &config.not_found) {
+        (Some(file), _) => (file, 200),
+        (None, Some(not_found)) if root.join(not_found.trim_start_matches('/')).is_file() => {
+            (root.join(not_found.trim_start_matches('/')), 404)
+        }
+        (None, _) => {
+            return Response::from_string("Not found")
+                .with_status_code(404)
+                .boxed()
+        }
+    };
+
+    let accepted = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Accept-Encoding"))
+        .map(|h| h.value.as_str().to_owned())
+        .unwrap_or_default();
+
+    let mut headers = vec![header("Content-Type", content_type(&file, config))];
+    let mut served = file.clone();
+
+    if config.compress.iter().any(|e| has_extension(&file, e)) {
+        headers.push(header("Vary", "Accept-Encoding"));
+
+        for algorithm in algorithms {
+            let sidecar = PathBuf::from(format!("{}.{}", file.display(), algorithm.extension()));
+
+            if accepted.contains(algorithm.name()) && sidecar.is_file() {
+                headers.push(header("Content-Encoding", algorithm.name()));
+                served = sidecar;
+                break;
+            }
+        }
+    }
+
+    match File::open(&served) {
+        Ok(file) => {
+            let mut response = Response::from_file(file).with_status_code(status);
+            for header in headers {
+                response.add_header(header);
+            }
+            response.boxed()
+        }
+        Err(e) => Response::from_string(e.to_string())
+            .with_status_code(500)
+            .boxed(),
+    }
+}
+
+/// Applies the same `try_files` sequence as the Caddy config generated by the server
+fn resolve(path: &str, config: &BundleConfig, root: &Path) -> Option<PathBuf> {
+    config
+        .try_files()
+        .iter()
+        .map(|candidate| candidate.replace("{path}", path))
+        .chain(config.fallback.clone())
+        .filter_map(|candidate| {
+            let relative = Path::new(candidate.trim_start_matches('/'));
+
+            relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+                .then(|| root.join(relative))
+        })
+        .find(|candidate| candidate.is_file())
+}
+
+fn copy_dir(source: &Path, destination: &Path) -> std::io::Result<()> {
+    for entry in WalkDir::new(source) {
+        let entry = entry?;
+        let target = destination.join(entry.path().strip_prefix(source).unwrap_or(entry.path()));
+
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(target)?;
+        } else if entry.file_type().is_file() {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension()
+        .map(|e| e.eq_ignore_ascii_case(extension))
+        .unwrap_or_default()
+}
+
+fn content_type<'a>(path: &Path, config: &'a BundleConfig) -> &'a str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    let configured = config
+        .mime_types
+        .iter()
+        .find(|(e, _)| e.trim_start_matches('.').eq_ignore_ascii_case(&extension));
+
+    if let Some((_, mime_type)) = configured {
+        return mime_type;
+    }
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).expect("invalid header")
+}
@@ -0,0 +1,11 @@
+//! Deploys static sites to a self-hosted server which serves them through Caddy.
+//!
+//! The [`api`] module exposes the client side operations for embedding them into other tools.
+
+pub mod api;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod server;
+pub mod shared;
+
+pub use shared::*;
@@ -1,12 +1,21 @@
+use clap::{Parser, Subcommand};
 #[cfg(feature = "client")]
-mod client;
-mod server;
-mod shared;
-
-use clap::Parser;
-use shared::*;
+use launch::client;
+use launch::server;
+use std::process::ExitCode;
 
 #[derive(Parser)]
+#[cfg_attr(feature = "client", command(after_help = client::EXIT_CODES_HELP))]
+struct Cli {
+    #[cfg(feature = "client")]
+    #[command(flatten)]
+    global: client::GlobalOptions,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
 enum Command {
     Server,
 
@@ -15,12 +24,25 @@ enum Command {
     Client(client::Command),
 }
 
-fn main() -> anyhow::Result<()> {
-    let command = Command::parse();
+fn main() -> ExitCode {
+    let cli = Cli::parse();
 
-    match command {
+    let result = match cli.command {
         Command::Server => server::run(),
         #[cfg(feature = "client")]
-        Command::Client(cmd) => client::run(cmd),
+        Command::Client(cmd) => client::run(cmd, cli.global),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Error: {error:?}");
+
+            #[cfg(feature = "client")]
+            return client::ExitCode::of(&error).into();
+
+            #[cfg(not(feature = "client"))]
+            ExitCode::FAILURE
+        }
     }
 }
@@ -1,8 +1,10 @@
-use super::Algorithm;
+use super::{compressor::Stripped, Algorithm};
+use crate::{BundleConfig, RateLimit, Redirect};
 use serde::Serialize;
 use serde_json::{json, Map, Value};
 use std::{
     collections::{BTreeMap, HashMap},
+    io::Read,
     path::PathBuf,
 };
 
@@ -19,16 +21,42 @@ pub struct TlsConfig {
     pub subjects: Vec<String>,
     pub email: String,
     pub token: String,
-    pub staging: bool,
+    pub ca: CaEndpoint,
+    /// Whether plain HTTP requests to hosts served over TLS are redirected to HTTPS
+    pub redirect: bool,
+}
+
+/// ACME directory used to issue certificates
+#[derive(Clone)]
+pub enum CaEndpoint {
+    LetsEncrypt { staging: bool },
+    Custom(String),
 }
 
 #[derive(Clone)]
 pub struct HttpConfig {
     pub port: u16,
+    /// Plain HTTP port for hosts which opted out of TLS, present if TLS is enabled
+    pub insecure_port: Option<u16>,
+    /// Answers requests on the insecure port which no plain HTTP host matched with a redirect
+    pub https_redirect: bool,
+    /// Replaces the routes of all hosts while set
+    pub maintenance: Option<Maintenance>,
     pub hosts: Vec<HostConfig>,
     pub domains: Vec<String>,
+    pub logs: Option<AccessLogs>,
 }
 
+/// Answers every request with a 503, serving the given HTML page if there is one
+#[derive(Clone)]
+pub struct Maintenance {
+    pub page: Option<PathBuf>,
+}
+
+/// Directory in which each host gets its own access log file
+#[derive(Clone)]
+pub struct AccessLogs(pub PathBuf);
+
 /// Location where Caddy stores certificates and such
 #[derive(Clone)]
 pub struct Storage(pub PathBuf);
@@ -36,23 +64,62 @@ pub struct Storage(pub PathBuf);
 #[derive(Clone)]
 pub struct HostConfig {
     pub hosts: Vec<String>,
+    /// Path prefix the bundle is mounted at, stripped before files are looked up
+    pub prefix: Option<String>,
     pub root: FileRoot,
     pub server: FileServer,
     pub fallback: Option<Fallback>,
+    pub not_found: Option<NotFound>,
+    /// Files whose originals were emptied and can only be served precompressed
+    pub stripped: Option<StrippedFiles>,
+    /// Requests allowed per client IP, answered with a 429 beyond it
+    pub rate_limit: Option<RateLimit>,
+    /// Redirects answered before anything else
+    pub redirects: Vec<Redirect>,
+    /// Content types set for files with these extensions instead of the detected ones
+    pub mime_types: BTreeMap<String, String>,
+    /// Raw routes supplied by the bundle, inserted ahead of the file server
+    pub extra: Vec<Value>,
+    /// Whether the host is served on the TLS listener, if there is one
+    pub tls: bool,
+}
+
+/// Rewrites requests to the first existing candidate, ending with an optional fallback path
+#[derive(Clone)]
+pub struct Fallback {
+    pub candidates: Vec<String>,
+    pub path: Option<String>,
 }
 
-/// Rewrites unmatched requests to the given path
+/// Serves the given path with a 404 status if none of the candidates exist
 #[derive(Clone)]
-pub struct Fallback(pub String);
+pub struct NotFound {
+    pub candidates: Vec<String>,
+    pub path: String,
+}
+
+/// Paths which have to be served through one of the sidecars, as their original is empty
+#[derive(Clone)]
+pub struct StrippedFiles {
+    pub paths: Vec<String>,
+    pub compression: Vec<Algorithm>,
+}
 
 /// Sets the root for the match
 #[derive(Clone)]
 pub struct FileRoot(pub PathBuf);
 
 /// Serves files and allows precompressed sidecars
+///
+/// Caddy answers conditional requests with a 304 based on the ETag and Last-Modified headers
+/// it derives from the file (or sidecar) size and mtime, both of which are kept stable.
 #[derive(Clone)]
 pub struct FileServer {
     pub compression: Vec<Algorithm>,
+    /// Renders a listing for directories without an index file
+    pub browse: bool,
+    /// Index file looked up for directories, Caddy's defaults apply if unset
+    pub index: Option<String>,
 }
 
 impl CaddyConfig {
@@ -61,45 +128,301 @@ impl CaddyConfig {
         hosts: Vec<HostConfig>,
         storage_dir: PathBuf,
         tls: Option<TlsConfig>,
+        log_dir: Option<PathBuf>,
+        maintenance: Option<Maintenance>,
     ) -> Self {
         let port = if tls.is_some() { 443 } else { 80 };
+        let insecure_port = tls.as_ref().map(|_| 80);
+        let https_redirect = tls.as_ref().map(|tls| tls.redirect).unwrap_or_default();
 
         Self {
             http: HttpConfig {
                 domains,
                 hosts,
                 port,
+                insecure_port,
+                https_redirect,
+                maintenance,
+                logs: log_dir.map(AccessLogs),
             },
             storage: Storage(storage_dir),
             tls,
         }
     }
 
-    pub fn apply(&self, admin_url: &str) -> Result<(), ureq::Error> {
-        ureq::post(&format!("{}/load", admin_url))
-            .send_json(&self)
-            .map(|_| ())
+    pub fn apply(&self, admin_url: &str) -> Result<(), ApplyError> {
+        let body = serde_json::to_vec(self).map_err(|e| ApplyError::Unreachable(e.to_string()))?;
+        admin_request(admin_url, "POST", "/load", Some(&body)).map(|_| ())
+    }
+
+    /// Fetches the config Caddy is currently running with
+    pub fn live(admin_url: &str) -> Result<Value, ApplyError> {
+        let body = admin_request(admin_url, "GET", "/config/", None)?;
+        serde_json::from_slice(&body).map_err(|e| ApplyError::Unreachable(e.to_string()))
+    }
+}
+
+/// Sends a request to the admin API, which is either a HTTP URL or a `unix:/path/to/socket`
+fn admin_request(
+    admin_url: &str,
+    method: &str,
+    path: &str,
+    body: Option<&[u8]>,
+) -> Result<Vec<u8>, ApplyError> {
+    if let Some(socket) = admin_url.strip_prefix("unix:") {
+        return unix_request(socket, method, path, body);
+    }
+
+    let request = ureq::request(method, &format!("{admin_url}{path}"));
+    let result = match body {
+        Some(body) => request
+            .set("Content-Type", "application/json")
+            .send_bytes(body),
+        None => request.call(),
+    };
+
+    match result {
+        Ok(response) => {
+            let mut body = Vec::new();
+            response
+                .into_reader()
+                .read_to_end(&mut body)
+                .map_err(|e| ApplyError::Unreachable(e.to_string()))?;
+            Ok(body)
+        }
+        Err(ureq::Error::Status(status, response)) => Err(ApplyError::Rejected {
+            status,
+            body: response.into_string().unwrap_or_default(),
+        }),
+        Err(ureq::Error::Transport(transport)) => {
+            Err(ApplyError::Unreachable(transport.to_string()))
+        }
     }
 }
 
+/// Speaks plain HTTP/1.0 over a unix socket, ureq has no way to swap out its TCP transport.
+/// The request carries no `Host` header, which is what Caddy expects on socket listeners.
+#[cfg(unix)]
+fn unix_request(
+    socket: &str,
+    method: &str,
+    path: &str,
+    body: Option<&[u8]>,
+) -> Result<Vec<u8>, ApplyError> {
+    use std::{io::Write, os::unix::net::UnixStream};
+
+    let unreachable = |e: std::io::Error| ApplyError::Unreachable(format!("{socket}: {e}"));
+    let mut stream = UnixStream::connect(socket).map_err(unreachable)?;
+
+    let body = body.unwrap_or_default();
+    write!(
+        stream,
+        "{method} {path} HTTP/1.0\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    )
+    .and_then(|_| stream.write_all(body))
+    .map_err(unreachable)?;
+
+    // HTTP/1.0 responses are never chunked and end when the connection is closed
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).map_err(unreachable)?;
+
+    let malformed = || ApplyError::Unreachable(format!("{socket}: malformed response"));
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(malformed)?;
+    let status = std::str::from_utf8(&response[..split])
+        .ok()
+        .and_then(|head| head.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(malformed)?;
+    let body = response[split + 4..].to_vec();
+
+    match status {
+        200..=299 => Ok(body),
+        status => Err(ApplyError::Rejected {
+            status,
+            body: String::from_utf8_lossy(&body).into(),
+        }),
+    }
+}
+
+#[cfg(not(unix))]
+fn unix_request(
+    socket: &str,
+    _method: &str,
+    _path: &str,
+    _body: Option<&[u8]>,
+) -> Result<Vec<u8>, ApplyError> {
+    Err(ApplyError::Unreachable(format!(
+        "{socket}: unix sockets are not supported on this platform"
+    )))
+}
+
+/// Reason why Caddy did not accept a new configuration
+#[derive(Debug)]
+pub enum ApplyError {
+    /// Caddy responded with an error, usually because the config is invalid
+    Rejected { status: u16, body: String },
+    /// The admin endpoint could not be reached
+    Unreachable(String),
+}
+
+impl std::fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApplyError::Rejected { status, body } => {
+                write!(f, "caddy rejected config ({status}): {}", body.trim())
+            }
+            ApplyError::Unreachable(e) => write!(f, "caddy unreachable: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ApplyError {}
+
 impl HostConfig {
     pub fn new(
         hosts: Vec<String>,
         root: PathBuf,
         compression: Vec<Algorithm>,
-        fallback: Option<String>,
+        config: &BundleConfig,
+        stripped: &Stripped,
     ) -> Self {
+        let candidates = config.try_files();
+
+        let fallback = match (&config.fallback, &config.try_files) {
+            (None, None) => None,
+            (path, _) => Some(Fallback {
+                candidates: candidates.clone(),
+                path: path.clone(),
+            }),
+        };
+
+        let not_found = config
+            .not_found
+            .clone()
+            .map(|path| NotFound { candidates, path });
+
+        // Caddy's defaults, unless the bundle picks its own index file
+        let index_names = match &config.index {
+            Some(index) => vec![index.as_str()],
+            None => vec!["index.html", "index.txt"],
+        };
+
+        // Directories are answered with their index file, which may be stripped as well
+        let mut paths = Vec::new();
+        for path in stripped.keys() {
+            let path = format!("/{}", path.to_string_lossy().replace('\\', "/"));
+
+            if let Some((directory, name)) = path.rsplit_once('/') {
+                if index_names.contains(&name) {
+                    paths.push(format!("{directory}/"));
+                }
+            }
+
+            paths.push(path);
+        }
+
+        // Sorted, as the config is compared with the live one to detect drift
+        paths.sort();
+        paths.dedup();
+
+        let stripped = (!paths.is_empty()).then(|| StrippedFiles {
+            paths,
+            compression: compression.clone(),
+        });
+
         Self {
             hosts,
+            prefix: config.path_prefix(),
             root: FileRoot(root),
-            server: FileServer { compression },
-            fallback: fallback.map(Fallback),
+            server: FileServer {
+                compression,
+                browse: config.browse,
+                index: config.index.clone(),
+            },
+            fallback,
+            not_found,
+            stripped,
+            rate_limit: config.rate_limit,
+            redirects: config.redirects.clone(),
+            mime_types: config
+                .mime_types
+                .iter()
+                .map(|(extension, mime_type)| {
+                    (
+                        extension.trim_start_matches('.').to_owned(),
+                        mime_type.clone(),
+                    )
+                })
+                .collect(),
+            extra: match &config.caddy_extra {
+                Some(Value::Array(routes)) => routes.clone(),
+                _ => Vec::new(),
+            },
+            tls: config.tls,
         }
     }
 }
 
+impl AccessLogs {
+    /// Caddy logger names may not contain dots, so they are derived from the host
+    fn logger_name(host: &str) -> String {
+        host.replace(['.', '*'], "_")
+    }
+
+    fn logger_names(&self, hosts: &[String]) -> Value {
+        let names = hosts
+            .iter()
+            .map(|host| (host.clone(), Value::String(AccessLogs::logger_name(host))))
+            .collect::<Map<_, _>>();
+
+        json!({ "logger_names": names })
+    }
+
+    fn logging(&self, hosts: &[String]) -> Value {
+        let mut logs = Map::new();
+
+        logs.insert("default".into(), json!({ "exclude": ["http.log.access"] }));
+
+        for host in hosts {
+            let name = AccessLogs::logger_name(host);
+
+            logs.insert(
+                name.clone(),
+                json!({
+                    "writer": {
+                        "output": "file",
+                        "filename": self.0.join(format!("{host}.log"))
+                    },
+                    "include": [format!("http.log.access.{name}")]
+                }),
+            );
+        }
+
+        json!({ "logs": logs })
+    }
+}
+
+impl HttpConfig {
+    fn host_names(&self) -> Vec<String> {
+        self.hosts
+            .iter()
+            .flat_map(|host| host.hosts.iter().cloned())
+            .collect()
+    }
+}
+
 impl Into<Value> for CaddyConfig {
     fn into(self) -> Value {
+        let logging = self
+            .http
+            .logs
+            .as_ref()
+            .map(|logs| logs.logging(&self.http.host_names()));
+
         let storage: Value = self.storage.into();
         let http: Value = self.http.into();
 
@@ -110,20 +433,53 @@ impl Into<Value> for CaddyConfig {
             apps.insert("tls", tls.into());
         }
 
-        json!({
+        let mut config = json!({
             "storage": storage,
             "apps": apps
-        })
+        });
+
+        if let Some(logging) = logging {
+            config["logging"] = logging;
+        }
+
+        config
+    }
+}
+
+impl CaEndpoint {
+    pub fn directory(&self) -> &str {
+        match self {
+            CaEndpoint::LetsEncrypt { staging: true } => {
+                "https://acme-staging-v02.api.letsencrypt.org/directory"
+            }
+            CaEndpoint::LetsEncrypt { staging: false } => {
+                "https://acme-v02.api.letsencrypt.org/directory"
+            }
+            CaEndpoint::Custom(url) => url,
+        }
+    }
+}
+
+impl From<String> for CaEndpoint {
+    /// Accepts `letsencrypt`, `letsencrypt-staging` or an arbitrary ACME directory URL
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "letsencrypt" => CaEndpoint::LetsEncrypt { staging: false },
+            "letsencrypt-staging" => CaEndpoint::LetsEncrypt { staging: true },
+            _ => CaEndpoint::Custom(value),
+        }
+    }
+}
+
+impl Default for CaEndpoint {
+    fn default() -> Self {
+        CaEndpoint::LetsEncrypt { staging: false }
     }
 }
 
 impl Into<Value> for TlsConfig {
     fn into(self) -> Value {
-        let ca = if self.staging {
-            "https://acme-staging-v02.api.letsencrypt.org/directory"
-        } else {
-            "https://acme-v02.api.letsencrypt.org/directory"
-        };
+        let ca = self.ca.directory();
 
         json!({
             "automation": {
@@ -149,27 +505,131 @@ impl Into<Value> for TlsConfig {
     }
 }
 
+impl HttpConfig {
+    fn server(
+        port: u16,
+        hosts: Vec<HostConfig>,
+        domains: &[String],
+        logs: Option<&AccessLogs>,
+        https_redirect: bool,
+        maintenance: Option<&Maintenance>,
+    ) -> Value {
+        let host_names = hosts
+            .iter()
+            .flat_map(|host| host.hosts.iter().cloned())
+            .collect::<Vec<_>>();
+
+        let mut hosts = hosts;
+        // Caddy stops at the first matching route, so the longest prefix has to come first
+        hosts.sort_by_key(|host| std::cmp::Reverse(host.prefix.as_ref().map(String::len)));
+
+        let mut routes: Vec<Value> = match maintenance {
+            Some(maintenance) => vec![maintenance.clone().into()],
+            None => hosts.into_iter().map(Into::into).collect(),
+        };
+
+        // Host routes end in a file server which responds, so this only sees unmatched requests
+        if https_redirect {
+            routes.push(json!({
+                "handle": [{
+                    "handler": "static_response",
+                    "status_code": 308,
+                    "headers": {
+                        "Location": ["https://{http.request.host}{http.request.uri}"]
+                    }
+                }]
+            }));
+        }
+
+        let mut server = json!({
+            "listen": [format!(":{}", port)],
+            "routes": [{
+                "handle": [{
+                    "handler": "subroute",
+                    "routes": routes
+                }],
+                "match": [{
+                    "host": domains
+                }],
+                "terminal": true
+            }]
+        });
+
+        if let Some(logs) = logs {
+            server["logs"] = logs.logger_names(&host_names);
+        }
+
+        server
+    }
+}
+
 impl Into<Value> for HttpConfig {
     fn into(self) -> Value {
-        let routes: Vec<Value> = self.hosts.into_iter().map(Into::into).collect();
+        let mut servers = Map::new();
 
-        json!({
-            "servers": {
-                "srv0": {
-                    "listen": [format!(":{}", self.port)],
-                    "routes": [{
-                        "handle": [{
-                            "handler": "subroute",
-                            "routes": routes
-                        }],
-                        "match": [{
-                            "host": self.domains
-                        }],
-                        "terminal": true
-                    }]
-                }
-            }
-        })
+        let (secure, insecure) = match self.insecure_port {
+            Some(_) => self.hosts.into_iter().partition(|host| host.tls),
+            None => (self.hosts, Vec::new()),
+        };
+
+        servers.insert(
+            "srv0".into(),
+            HttpConfig::server(
+                self.port,
+                secure,
+                &self.domains,
+                self.logs.as_ref(),
+                false,
+                self.maintenance.as_ref(),
+            ),
+        );
+
+        if let (Some(port), true) = (
+            self.insecure_port,
+            self.https_redirect || !insecure.is_empty(),
+        ) {
+            servers.insert(
+                "srv1".into(),
+                HttpConfig::server(
+                    port,
+                    insecure,
+                    &self.domains,
+                    self.logs.as_ref(),
+                    self.https_redirect,
+                    self.maintenance.as_ref(),
+                ),
+            );
+        }
+
+        json!({ "servers": servers })
+    }
+}
+
+impl From<Maintenance> for Value {
+    fn from(maintenance: Maintenance) -> Self {
+        let page = maintenance.page.as_ref().and_then(|page| {
+            Some((
+                page.parent()?,
+                page.file_name()?.to_string_lossy().into_owned(),
+            ))
+        });
+
+        match page {
+            Some((dir, file)) => json!({
+                "handle": [
+                    { "handler": "vars", "root": dir },
+                    { "handler": "rewrite", "uri": format!("/{file}") },
+                    { "handler": "file_server", "status_code": 503 }
+                ]
+            }),
+            None => json!({
+                "handle": [{
+                    "handler": "static_response",
+                    "status_code": 503,
+                    "body": "Down for maintenance, back soon."
+                }]
+            }),
+        }
     }
 }
 
@@ -186,28 +646,196 @@ impl Into<Value> for HostConfig {
     fn into(self) -> Value {
         let mut routes: Vec<Value> = vec![];
 
+        // Provided by the `github.com/mholt/caddy-ratelimit` module, which stock Caddy lacks
+        if let Some(limit) = self.rate_limit {
+            let zone = self.hosts.join("_").replace(['.', '*'], "_")
+                + &self.prefix.as_deref().unwrap_or_default().replace('/', "_");
+
+            routes.push(json!({
+                "handle": [{
+                    "handler": "rate_limit",
+                    "rate_limits": {
+                        zone: {
+                            "key": "{http.request.remote.host}",
+                            "window": format!("{}s", limit.window),
+                            "max_events": limit.requests
+                        }
+                    }
+                }]
+            }));
+        }
+
+        if let Some(prefix) = &self.prefix {
+            routes.push(json!({
+                "handle": [{
+                    "handler": "rewrite",
+                    "strip_path_prefix": prefix
+                }]
+            }));
+        }
+
+        for (index, redirect) in self.redirects.into_iter().enumerate() {
+            routes.push(redirect_route(index, redirect));
+        }
+
         routes.push(self.root.into());
 
+        // The file server only detects a content type if none has been set yet
+        for (extension, mime_type) in self.mime_types {
+            routes.push(json!({
+                "handle": [{
+                    "handler": "headers",
+                    "response": {
+                        "set": {
+                            "Content-Type": [mime_type]
+                        }
+                    }
+                }],
+                "match": [{
+                    "path": [format!("*.{extension}")]
+                }]
+            }));
+        }
+
+        // Placed before the fallback so it can not rewrite requests meant for these routes
+        routes.extend(self.extra);
+
         if let Some(fallback) = self.fallback {
             routes.push(fallback.into())
         }
 
+        // Matched after the fallback, which may rewrite requests to a stripped file
+        if let Some(stripped) = self.stripped {
+            routes.extend(stripped.routes());
+        }
+
+        if let Some(not_found) = self.not_found {
+            routes.push(not_found.route(self.server.clone()));
+        }
+
         routes.push(self.server.into());
 
+        let mut matcher = json!({ "host": self.hosts });
+
+        if let Some(prefix) = &self.prefix {
+            matcher["path"] = json!([prefix, format!("{prefix}/*")]);
+        }
+
         json!({
             "handle": [{
                 "handler": "subroute",
                 "routes": routes
             }],
-            "match": [{
-                "host": self.hosts
-            }]
+            "match": [matcher]
         })
     }
 }
 
+impl StrippedFiles {
+    /// Lets clients without an `Accept-Encoding` header receive a sidecar, as they accept any
+    /// encoding (RFC 9110), while those ruling out all of them get a 406 instead of nothing
+    fn routes(self) -> [Value; 2] {
+        let names = self
+            .compression
+            .iter()
+            .map(|algorithm| algorithm.name())
+            .collect::<Vec<_>>();
+
+        let patterns = names
+            .iter()
+            .map(|name| format!("*{name}*"))
+            .collect::<Vec<_>>();
+
+        [
+            json!({
+                "handle": [{
+                    "handler": "headers",
+                    "request": {
+                        "set": {
+                            "Accept-Encoding": [names.join(", ")]
+                        }
+                    }
+                }],
+                "match": [{
+                    "path": self.paths,
+                    "header": { "Accept-Encoding": null }
+                }]
+            }),
+            json!({
+                "handle": [{
+                    "handler": "static_response",
+                    "status_code": 406,
+                    "body": format!("Only available with content encoding {}", names.join(" or "))
+                }],
+                "match": [{
+                    "path": self.paths,
+                    "not": [{
+                        "header": { "Accept-Encoding": patterns }
+                    }]
+                }],
+                "terminal": true
+            }),
+        ]
+    }
+}
+
+/// Responds with a redirect, carrying the remainder of prefix matches over as `:splat`
+fn redirect_route(index: usize, redirect: Redirect) -> Value {
+    let (matcher, location) = match redirect.prefix() {
+        Some(prefix) => {
+            let name = format!("redirect{index}");
+            let location = redirect
+                .to
+                .replace(":splat", &format!("{{http.regexp.{name}.1}}"));
+            let matcher = json!({
+                "path_regexp": {
+                    "name": name,
+                    "pattern": format!("^{}(.*)$", escape_regex(prefix))
+                }
+            });
+
+            (matcher, location)
+        }
+        None => (json!({ "path": [redirect.from] }), redirect.to),
+    };
+
+    json!({
+        "handle": [{
+            "handler": "static_response",
+            "status_code": redirect.status,
+            "headers": {
+                "Location": [location]
+            }
+        }],
+        "match": [matcher],
+        "terminal": true
+    })
+}
+
+/// Escapes characters with a meaning in Go regular expressions
+fn escape_regex(literal: &str) -> String {
+    let mut escaped = String::with_capacity(literal.len());
+
+    for c in literal.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
+}
+
 impl Into<Value> for Fallback {
     fn into(self) -> Value {
+        let try_files = self
+            .candidates
+            .into_iter()
+            .map(|candidate| candidate.replace("{path}", "{http.request.uri.path}"))
+            .chain(self.path)
+            .collect::<Vec<_>>();
+
         json!({
             "handle": [{
                 "handler": "rewrite",
@@ -215,11 +843,7 @@ impl Into<Value> for Fallback {
             }],
             "match": [{
                 "file": {
-                    "try_files": [
-                        "{http.request.uri.path}",
-                        "{http.request.uri.path}/index.html",
-                        self.0
-                    ]
+                    "try_files": try_files
                 }
             }]
         })
@@ -237,8 +861,36 @@ impl Into<Value> for FileRoot {
     }
 }
 
-impl Into<Value> for FileServer {
-    fn into(self) -> Value {
+impl NotFound {
+    fn route(self, server: FileServer) -> Value {
+        let try_files = self
+            .candidates
+            .into_iter()
+            .map(|candidate| candidate.replace("{path}", "{http.request.uri.path}"))
+            .collect::<Vec<_>>();
+
+        json!({
+            "handle": [
+                {
+                    "handler": "rewrite",
+                    "uri": self.path
+                },
+                server.handler(Some(404))
+            ],
+            "match": [{
+                "not": [{
+                    "file": {
+                        "try_files": try_files
+                    }
+                }]
+            }],
+            "terminal": true
+        })
+    }
+}
+
+impl FileServer {
+    fn handler(self, status_code: Option<u16>) -> Value {
         let algorithms = self
             .compression
             .into_iter()
@@ -251,12 +903,32 @@ impl Into<Value> for FileServer {
             algorithms_map.insert(algorithm, Value::Object(Map::new()));
         }
 
+        let mut handler = json!({
+            "handler": "file_server",
+            "precompressed": algorithms_map,
+            "precompressed_order": algorithms
+        });
+
+        if let Some(status_code) = status_code {
+            handler["status_code"] = status_code.into();
+        }
+
+        if self.browse {
+            handler["browse"] = json!({});
+        }
+
+        if let Some(index) = self.index {
+            handler["index_names"] = json!([index]);
+        }
+
+        handler
+    }
+}
+
+impl Into<Value> for FileServer {
+    fn into(self) -> Value {
         json!({
-            "handle": [{
-                "handler": "file_server",
-                "precompressed": algorithms_map,
-                "precompressed_order": algorithms
-            }]
+            "handle": [self.handler(None)]
         })
     }
 }
@@ -1,13 +1,18 @@
-use brotli::enc::BrotliEncoderParams;
-use flate2::{write::GzEncoder, Compression};
+use crate::shared::{BrotliMode, BrotliOptions, BundleConfig, CompressionProfile};
+use brotli::enc::{backward_references::BrotliEncoderMode, BrotliEncoderParams};
+use filetime::FileTime;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
     collections::HashMap,
-    fs::File,
-    io::{self, Seek},
-    path::Path,
+    fs::{self, File},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc::Receiver, Mutex},
+    thread,
 };
-use walkdir::{DirEntry, WalkDir};
+use walkdir::WalkDir;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Statistics {
@@ -17,17 +22,105 @@ pub struct Statistics {
     pub compressible: u64,
     /// Size of compressed files by algorithm
     pub compressed: HashMap<Algorithm, u64>,
+    /// Levels the files were compressed at, bundles predating profiles used the best one
+    #[serde(default)]
+    pub profile: CompressionProfile,
+}
+
+impl Statistics {
+    /// Fraction of the total size saved when serving the best available sidecars
+    pub fn savings(&self) -> f64 {
+        match self.compressed.values().min() {
+            Some(compressed) if self.size > 0 => {
+                self.compressible.saturating_sub(*compressed) as f64 / self.size as f64
+            }
+            _ => 0.0,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Algorithm {
+    #[serde(alias = "gzip")]
     Gzip,
+    #[serde(alias = "brotli", alias = "br")]
     Brotli,
 }
 
+/// Number of leading bytes used to estimate whether a file is worth compressing
+const SAMPLE_SIZE: usize = 16 * 1024;
+/// Compression ratio of the sample above which a file is considered incompressible
+const SAMPLE_RATIO: f64 = 0.95;
+
+/// Content digests of compressed files, keyed by their path relative to the bundle root
+pub type Digests = HashMap<PathBuf, [u8; 32]>;
+
+/// Original sizes of files whose originals were emptied, keyed by their path relative to the
+/// bundle root
+pub type Stripped = HashMap<PathBuf, u64>;
+
+/// Sidecars of a previous deployment which can be reused for files that did not change
+pub struct Previous<'a> {
+    pub root: &'a Path,
+    pub digests: &'a Digests,
+}
+
+/// Running sums of a compression pass, kept per worker and merged at the end
+#[derive(Default)]
+struct Totals {
+    size: u64,
+    compressible: u64,
+    compressed: HashMap<Algorithm, u64>,
+    digests: Digests,
+    stripped: Stripped,
+    pending: Vec<Pending>,
+    /// Files named like sidecars, which are only counted once it is known that no original
+    /// file exists next to them
+    prebuilt: Vec<(PathBuf, u64)>,
+}
+
+/// Sidecars generated for a file which are moved into place once unpacking finished
+struct Pending {
+    relative: PathBuf,
+    size: u64,
+    mtime: FileTime,
+    /// Algorithm, staging path and size of each generated sidecar
+    sidecars: Vec<(Algorithm, PathBuf, u64)>,
+}
+
+impl Totals {
+    fn merge(&mut self, other: Totals) {
+        self.size += other.size;
+        self.compressible += other.compressible;
+        for (algorithm, compressed) in other.compressed {
+            *self.compressed.entry(algorithm).or_default() += compressed;
+        }
+
+        self.digests.extend(other.digests);
+        self.stripped.extend(other.stripped);
+        self.pending.extend(other.pending);
+        self.prebuilt.extend(other.prebuilt);
+    }
+}
+
+impl Totals {
+    fn finish(self, profile: CompressionProfile) -> (Statistics, Digests, Stripped) {
+        let stats = Statistics {
+            size: self.size,
+            compressible: self.compressible,
+            compressed: self.compressed,
+            profile,
+        };
+
+        (stats, self.digests, self.stripped)
+    }
+}
+
 pub struct Compressor {
     algorithms: Vec<Algorithm>,
     min_size: u64,
+    /// Decompresses every sidecar again and compares it with its original
+    verify: bool,
 }
 
 impl Compressor {
@@ -35,54 +128,319 @@ impl Compressor {
         self.algorithms.clone()
     }
 
-    pub fn compress(&self, dir: impl AsRef<Path>, filter: &[String]) -> io::Result<Statistics> {
-        let mut total_size = 0;
-        let mut total_compressible = 0;
-        let mut total_compressed = HashMap::new();
+    /// Algorithms used for a bundle, limited to those it selected if any
+    pub fn algorithms_for(&self, config: &BundleConfig) -> Vec<Algorithm> {
+        match &config.algorithms {
+            Some(selected) => self
+                .algorithms
+                .iter()
+                .copied()
+                .filter(|algorithm| selected.contains(algorithm))
+                .collect(),
+            None => self.algorithms(),
+        }
+    }
+
+    pub fn with_verification(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Location of the sidecar for a file at `relative` within `dir`
+    ///
+    /// Sidecars sit next to their source, which is the only place Caddy looks for them.
+    pub fn sidecar_path(&self, dir: &Path, relative: &Path, algorithm: Algorithm) -> PathBuf {
+        sidecar_path(&dir.join(relative), algorithm)
+    }
+
+    /// Whether a file within `dir` is a sidecar written by this compressor
+    pub fn is_sidecar(&self, path: &Path) -> bool {
+        self.named_like_sidecar(path) && path.with_extension("").is_file()
+    }
+
+    fn named_like_sidecar(&self, path: &Path) -> bool {
+        self.algorithms.iter().any(|algorithm| {
+            path.extension()
+                .map(|e| e.eq_ignore_ascii_case(algorithm.extension()))
+                .unwrap_or_default()
+        })
+    }
+
+    pub fn compress(
+        &self,
+        dir: impl AsRef<Path>,
+        config: &BundleConfig,
+        previous: Option<Previous>,
+    ) -> io::Result<(Statistics, Digests, Stripped)> {
+        let dir = dir.as_ref();
+        let mut totals = Totals::default();
 
-        for entry in WalkDir::new(dir) {
-            let entry = entry?;
-            let size = entry.metadata()?.len();
+        // Collected up front, as the walk would otherwise run into the sidecars written below
+        let entries = WalkDir::new(dir)
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
 
-            total_size += size;
+        for entry in entries {
+            let metadata = entry.metadata()?;
 
-            if size < self.min_size
-                || !entry.file_type().is_file()
-                || !match_extension(&entry, filter)
-            {
-                continue;
+            // Directory sizes depend on the file system, only file contents are counted
+            if metadata.is_file() {
+                if !self.is_sidecar(entry.path()) {
+                    totals.size += metadata.len();
+                }
+
+                let relative = entry.path().strip_prefix(dir).unwrap_or(entry.path());
+                self.compress_file(
+                    dir,
+                    relative,
+                    &metadata,
+                    config,
+                    previous.as_ref(),
+                    &mut totals,
+                )?;
             }
+        }
+
+        self.settle(dir, config, &mut totals)?;
+        Ok(totals.finish(config.profile))
+    }
+
+    /// Compresses files as their paths relative to `dir` arrive, until the sender is dropped
+    ///
+    /// This allows compression to start while the files are still being written, spreading
+    /// the work over one worker per available core.
+    pub fn compress_stream(
+        &self,
+        dir: &Path,
+        config: &BundleConfig,
+        previous: Option<Previous>,
+        files: Receiver<PathBuf>,
+    ) -> io::Result<(Statistics, Digests, Stripped)> {
+        let workers = thread::available_parallelism()
+            .map(usize::from)
+            .unwrap_or(1);
+        let files = Mutex::new(files);
 
-            total_compressible += size;
+        let results = thread::scope(|scope| {
+            let handles = (0..workers)
+                .map(|_| {
+                    scope.spawn(|| -> io::Result<Totals> {
+                        let mut totals = Totals::default();
 
-            for algorithm in self.algorithms.iter() {
-                let compressed = Compressor::apply(*algorithm, entry.path())?;
-                total_compressed.insert(*algorithm, compressed);
+                        loop {
+                            let next = files.lock().expect("file queue poisoned").recv();
+
+                            let relative = match next {
+                                Ok(relative) => relative,
+                                Err(_) => return Ok(totals),
+                            };
+
+                            let metadata = fs::metadata(dir.join(&relative))?;
+
+                            // The original of a prebuilt sidecar may not be unpacked yet
+                            if self.named_like_sidecar(&relative) {
+                                totals.prebuilt.push((relative.clone(), metadata.len()));
+                            } else {
+                                totals.size += metadata.len();
+                            }
+
+                            self.compress_file(
+                                dir,
+                                &relative,
+                                &metadata,
+                                config,
+                                previous.as_ref(),
+                                &mut totals,
+                            )?;
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("compression worker panicked"))
+                .collect::<Vec<_>>()
+        });
+
+        let mut totals = Totals::default();
+
+        for result in results {
+            totals.merge(result?);
+        }
+
+        // The sender is dropped once unpacking finished, so every original file is in place
+        for (relative, size) in std::mem::take(&mut totals.prebuilt) {
+            if !self.is_sidecar(&dir.join(relative)) {
+                totals.size += size;
             }
         }
 
-        Ok(Statistics {
-            size: total_size,
-            compressible: total_compressible,
-            compressed: total_compressed,
-        })
+        // Likewise, every prebuilt sidecar is in place
+        self.settle(dir, config, &mut totals)?;
+        Ok(totals.finish(config.profile))
+    }
+
+    fn compress_file(
+        &self,
+        dir: &Path,
+        relative: &Path,
+        metadata: &fs::Metadata,
+        config: &BundleConfig,
+        previous: Option<&Previous>,
+        totals: &mut Totals,
+    ) -> io::Result<()> {
+        let size = metadata.len();
+        let path = dir.join(relative);
+
+        if size < self.min_size || !match_extension(&path, &config.compress) {
+            return Ok(());
+        }
+
+        // Read the file once and feed every algorithm from memory
+        let source = fs::read(&path)?;
+
+        if !is_compressible(&source) {
+            return Ok(());
+        }
+
+        totals.compressible += size;
+        let mtime = FileTime::from_last_modification_time(metadata);
+
+        let digest: [u8; 32] = Sha256::digest(&source).into();
+        let unchanged = previous.filter(|previous| previous.digests.get(relative) == Some(&digest));
+        let mut sidecars = Vec::new();
+
+        for algorithm in self.algorithms_for(config) {
+            // Staged apart, as a prebuilt sidecar may still be unpacked at the destination
+            let staged = staged_path(&self.sidecar_path(dir, relative, algorithm));
+
+            if let Some(parent) = staged.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let reused = match unchanged {
+                Some(previous) => Compressor::reuse(
+                    &self.sidecar_path(previous.root, relative, algorithm),
+                    &staged,
+                    mtime,
+                )?,
+                None => None,
+            };
+
+            let compressed = match reused {
+                Some(compressed) => compressed,
+                None => Compressor::apply(algorithm, &staged, &source, mtime, config)?,
+            };
+
+            sidecars.push((algorithm, staged, compressed));
+        }
+
+        totals.pending.push(Pending {
+            relative: relative.to_path_buf(),
+            size,
+            mtime,
+            sidecars,
+        });
+        totals.digests.insert(relative.to_path_buf(), digest);
+
+        Ok(())
+    }
+
+    /// Copies the sidecar of an unchanged file from a previous deployment, if it exists
+    fn reuse(source: &Path, destination: &Path, mtime: FileTime) -> io::Result<Option<u64>> {
+        match fs::copy(source, destination) {
+            Ok(size) => {
+                filetime::set_file_mtime(destination, mtime)?;
+                Ok(Some(size))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Moves generated sidecars into place, keeping those shipped with the bundle if smaller
+    ///
+    /// Prebuilt sidecars may be unpacked after their original was compressed, so this has to
+    /// wait until every file of the bundle is in place.
+    fn settle(&self, dir: &Path, config: &BundleConfig, totals: &mut Totals) -> io::Result<()> {
+        for file in std::mem::take(&mut totals.pending) {
+            let path = dir.join(&file.relative);
+            let mut smaller = !file.sidecars.is_empty();
+
+            // Read before the original may be stripped below
+            let source = match self.verify {
+                true => Some(fs::read(&path)?),
+                false => None,
+            };
+
+            for (algorithm, staged, generated) in file.sidecars {
+                let destination = self.sidecar_path(dir, &file.relative, algorithm);
+                let compressed =
+                    Compressor::keep_smaller(&staged, &destination, generated, file.mtime)?;
+
+                // Covers prebuilt sidecars as well, whichever of them ends up being served
+                if let Some(source) = &source {
+                    algorithm.verify(&destination, source)?;
+                }
+
+                *totals.compressed.entry(algorithm).or_default() += compressed;
+                smaller &= compressed < file.size;
+            }
+
+            // Caddy only serves sidecars of existing files, so the original is emptied, not removed
+            if config.strip_originals && smaller {
+                File::create(&path)?;
+                filetime::set_file_mtime(&path, file.mtime)?;
+                totals.stripped.insert(file.relative, file.size);
+            }
+        }
+
+        Ok(())
     }
 
-    fn apply(algorithm: Algorithm, path: impl AsRef<Path>) -> io::Result<u64> {
-        let path = path.as_ref();
-        let extension = path.extension().expect("matched file without extension");
-        let destination_path = path.with_extension(format!(
-            "{}.{}",
-            extension
-                .to_str()
-                .expect("matched file with invalid extension"),
-            algorithm.extension()
-        ));
+    /// Moves a staged sidecar to `destination` unless the one shipped there is no larger
+    fn keep_smaller(
+        staged: &Path,
+        destination: &Path,
+        generated: u64,
+        mtime: FileTime,
+    ) -> io::Result<u64> {
+        let existing = match fs::metadata(destination) {
+            Ok(metadata) if metadata.is_file() => Some(metadata.len()),
+            _ => None,
+        };
+
+        let size = match existing {
+            Some(existing) if existing <= generated => {
+                fs::remove_file(staged)?;
+                existing
+            }
+            _ => {
+                fs::rename(staged, destination)?;
+                generated
+            }
+        };
+
+        filetime::set_file_mtime(destination, mtime)?;
 
-        let mut source = File::open(path)?;
+        Ok(size)
+    }
+
+    fn apply(
+        algorithm: Algorithm,
+        destination_path: &Path,
+        source: &[u8],
+        mtime: FileTime,
+        config: &BundleConfig,
+    ) -> io::Result<u64> {
         let mut destination = File::create(destination_path)?;
 
-        algorithm.compress(&mut source, &mut destination)?;
+        algorithm.compress(source, &mut destination, config)?;
+
+        // Caddy derives the ETag and Last-Modified headers of precompressed responses from the
+        // sidecar, so it inherits the mtime of its source to stay stable across redeploys.
+        filetime::set_file_mtime(destination_path, mtime)?;
 
         Ok(destination.stream_position()?)
     }
@@ -95,6 +453,7 @@ impl Default for Compressor {
         Compressor {
             algorithms: vec![Brotli, Gzip],
             min_size: 1_400,
+            verify: false,
         }
     }
 }
@@ -118,18 +477,88 @@ impl Algorithm {
         }
     }
 
-    fn compress(&self, source: &mut File, destination: &mut File) -> io::Result<()> {
+    /// Checks that a sidecar decompresses back to exactly the original contents
+    fn verify(self, sidecar: &Path, original: &[u8]) -> io::Result<()> {
+        use Algorithm::*;
+
+        let file = File::open(sidecar)?;
+        let mut decompressed = Vec::with_capacity(original.len());
+
+        let result = match self {
+            Gzip => GzDecoder::new(file).read_to_end(&mut decompressed),
+            Brotli => brotli::Decompressor::new(file, 64 * 1024).read_to_end(&mut decompressed),
+        };
+
+        if result.is_err() || decompressed != original {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} sidecar {} does not match its original",
+                    self.name(),
+                    sidecar.display()
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Quality levels the algorithm accepts, the last one being used by the best profile
+    pub fn levels(self) -> std::ops::RangeInclusive<u32> {
+        use Algorithm::*;
+
+        match self {
+            Gzip => 1..=9,
+            Brotli => 0..=11,
+        }
+    }
+
+    fn compress<W: Write>(
+        &self,
+        source: &[u8],
+        destination: &mut W,
+        config: &BundleConfig,
+    ) -> io::Result<()> {
+        let level = config.profile.level(*self);
+        self.compress_at(level, source, destination, &config.brotli)
+    }
+
+    /// Compresses `source` at a specific quality level, clamped to those in [`Self::levels`]
+    pub fn compress_at<W: Write>(
+        &self,
+        level: u32,
+        mut source: &[u8],
+        destination: &mut W,
+        options: &BrotliOptions,
+    ) -> io::Result<()> {
         use Algorithm::*;
 
+        let level = level.clamp(*self.levels().start(), *self.levels().end());
+
         match self {
             Gzip => {
-                let mut encoder = GzEncoder::new(destination, Compression::best());
-                io::copy(source, &mut encoder)?;
+                let mut encoder = GzEncoder::new(destination, Compression::new(level));
+                encoder.write_all(source)?;
                 encoder.finish()?;
             }
             Brotli => {
-                let params = BrotliEncoderParams::default();
-                brotli::BrotliCompress(source, destination, &params)?;
+                let mut params = BrotliEncoderParams {
+                    quality: level as i32,
+                    ..Default::default()
+                };
+
+                if let Some(window) = options.window {
+                    // Windows beyond 24 bits require the large window extension browsers lack
+                    params.lgwin = window.clamp(10, 24);
+                }
+
+                params.mode = match options.mode {
+                    BrotliMode::Generic => BrotliEncoderMode::BROTLI_MODE_GENERIC,
+                    BrotliMode::Text => BrotliEncoderMode::BROTLI_MODE_TEXT,
+                    BrotliMode::Font => BrotliEncoderMode::BROTLI_MODE_FONT,
+                };
+
+                brotli::BrotliCompress(&mut source, destination, &params)?;
             }
         }
 
@@ -137,8 +566,36 @@ impl Algorithm {
     }
 }
 
-fn match_extension(entry: &DirEntry, extensions: &[String]) -> bool {
-    if let Some(extension) = entry.path().extension() {
+/// Where a generated sidecar waits until it is known whether a prebuilt one is smaller
+fn staged_path(sidecar: &Path) -> PathBuf {
+    let mut staged = sidecar.as_os_str().to_owned();
+    staged.push(".launch-staged");
+    PathBuf::from(staged)
+}
+
+fn sidecar_path(path: &Path, algorithm: Algorithm) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".");
+    sidecar.push(algorithm.extension());
+    PathBuf::from(sidecar)
+}
+
+/// Quickly compresses a sample of the data to detect content which is already compressed
+///
+/// Files whose sample does not shrink below [`SAMPLE_RATIO`] are skipped, as running the
+/// full algorithms on them only burns CPU for sidecars Caddy would gain nothing from.
+pub fn is_compressible(data: &[u8]) -> bool {
+    let sample = &data[..data.len().min(SAMPLE_SIZE)];
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+
+    match encoder.write_all(sample).and_then(|_| encoder.finish()) {
+        Ok(compressed) => (compressed.len() as f64) < sample.len() as f64 * SAMPLE_RATIO,
+        Err(_) => true,
+    }
+}
+
+fn match_extension(path: &Path, extensions: &[String]) -> bool {
+    if let Some(extension) = path.extension() {
         for expected in extensions {
             if extension.eq_ignore_ascii_case(expected) {
                 return true;
@@ -148,3 +605,148 @@ fn match_extension(entry: &DirEntry, extensions: &[String]) -> bool {
 
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use temp_dir::TempDir;
+
+    /// Writes a few pages large and repetitive enough to be compressed, returning their paths
+    fn site(dir: &Path) -> Vec<PathBuf> {
+        let pages = ["index.html", "about.html", "docs/guide.html"];
+
+        for (index, page) in pages.iter().enumerate() {
+            let path = dir.join(page);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(
+                path,
+                format!("<p>page {index}</p>\n").repeat(200 * (index + 1)),
+            )
+            .unwrap();
+        }
+
+        pages.iter().map(PathBuf::from).collect()
+    }
+
+    fn config() -> BundleConfig {
+        serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "domain": "example.com",
+            "compress": ["html"],
+            "fallback": null,
+        }))
+        .unwrap()
+    }
+
+    /// Combined size of the sidecars written for each algorithm
+    fn sidecar_sizes(compressor: &Compressor, dir: &Path, pages: &[PathBuf]) -> Vec<u64> {
+        compressor
+            .algorithms()
+            .into_iter()
+            .map(|algorithm| {
+                pages
+                    .iter()
+                    .map(|page| {
+                        let sidecar = compressor.sidecar_path(dir, page, algorithm);
+                        fs::metadata(sidecar).unwrap().len()
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sums_compressed_sizes() {
+        let dir = TempDir::new().unwrap();
+        let pages = site(dir.path());
+        let compressor = Compressor::default();
+
+        let (stats, _, _) = compressor.compress(dir.path(), &config(), None).unwrap();
+
+        let expected = sidecar_sizes(&compressor, dir.path(), &pages);
+        let compressed = compressor
+            .algorithms()
+            .iter()
+            .map(|algorithm| stats.compressed[algorithm])
+            .collect::<Vec<_>>();
+
+        assert_eq!(compressed, expected);
+    }
+
+    #[test]
+    fn sums_compressed_sizes_across_workers() {
+        let dir = TempDir::new().unwrap();
+        let pages = site(dir.path());
+        let compressor = Compressor::default();
+
+        let (sender, receiver) = mpsc::channel();
+        pages
+            .iter()
+            .for_each(|page| sender.send(page.clone()).unwrap());
+        drop(sender);
+
+        let (stats, _, _) = compressor
+            .compress_stream(dir.path(), &config(), None, receiver)
+            .unwrap();
+
+        let expected = sidecar_sizes(&compressor, dir.path(), &pages);
+        let compressed = compressor
+            .algorithms()
+            .iter()
+            .map(|algorithm| stats.compressed[algorithm])
+            .collect::<Vec<_>>();
+
+        assert_eq!(compressed, expected);
+    }
+
+    /// Writes a prebuilt sidecar for each page, returning the original size of the site
+    fn prebuilt_site(dir: &Path) -> (Vec<PathBuf>, u64) {
+        let pages = site(dir);
+        let original = pages
+            .iter()
+            .map(|page| fs::metadata(dir.join(page)).unwrap().len())
+            .sum::<u64>();
+
+        for page in pages.iter() {
+            let sidecar = Compressor::default().sidecar_path(dir, page, Algorithm::Gzip);
+            fs::write(sidecar, b"prebuilt").unwrap();
+        }
+
+        (pages, original)
+    }
+
+    #[test]
+    fn counts_only_original_files() {
+        let dir = TempDir::new().unwrap();
+        let (_, original) = prebuilt_site(dir.path());
+
+        let (stats, _, _) = Compressor::default()
+            .compress(dir.path(), &config(), None)
+            .unwrap();
+
+        assert_eq!(stats.size, original);
+    }
+
+    #[test]
+    fn counts_only_original_files_across_workers() {
+        let dir = TempDir::new().unwrap();
+        let (pages, original) = prebuilt_site(dir.path());
+        let compressor = Compressor::default();
+
+        // Sidecars arrive before their originals, as they may in an archive
+        let (sender, receiver) = mpsc::channel();
+        for page in pages.iter() {
+            let sidecar = compressor.sidecar_path(Path::new(""), page, Algorithm::Gzip);
+            sender.send(sidecar).unwrap();
+            sender.send(page.clone()).unwrap();
+        }
+        drop(sender);
+
+        let (stats, _, _) = compressor
+            .compress_stream(dir.path(), &config(), None, receiver)
+            .unwrap();
+
+        assert_eq!(stats.size, original);
+    }
+}
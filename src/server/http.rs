@@ -1,15 +1,27 @@
 use super::{
-    caddy::CaddyConfig, compressor::Compressor, manager::BundleManager, storage::BundleStorage,
+    caddy::{ApplyError, CaddyConfig, Maintenance},
+    compressor::{Compressor, Statistics},
+    manager::BundleManager,
+    storage::BundleStorage,
+    webhook::DeployEvent,
     Options,
 };
+use crate::{
+    Bundle, Deployment, DomainChange, DomainConflict, HistoryEntry, ListEntry, LogEntry,
+    VersionInfo, ARCHIVE_ENCODING_HEADER, FORCE_HEADER, IDEMPOTENCY_HEADER, NDJSON,
+    OVERWRITE_HEADER, PREPARE_HEADER, TTL_HEADER, VERBOSE_HEADER,
+};
+use flate2::{read, write::GzEncoder, Compression};
+use serde_json::Value;
 use std::{
-    collections::HashMap,
-    io::{self, ErrorKind},
+    collections::{HashMap, HashSet, VecDeque},
+    io::{self, ErrorKind, Read, Write},
+    net::SocketAddr,
     process::Command,
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tiny_http::{Method, Request, Response};
+use tiny_http::{Header, Method, Request, Response};
 use ulid::Ulid;
 
 const INGRESS_UPDATE_SCRIPT: &str = r#"
@@ -24,47 +36,235 @@ if [ "$str" != "" ];then
 fi
 "#;
 
+/// How often bundles are checked for an expired time to live
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of recent events kept per bundle
+const LOG_CAPACITY: usize = 200;
+
+/// Number of bundles events are kept for, those with the oldest events are forgotten first
+const LOG_BUNDLES: usize = 1000;
+
+/// How long the result of an upload is kept to answer retries with the same idempotency key
+const IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Size below which responses are sent as is, as gzip would gain next to nothing
+const GZIP_THRESHOLD: usize = 1024;
+
+/// Error which is reported to the client with a specific status code
+#[derive(Debug)]
+struct StatusError {
+    code: u16,
+    message: String,
+}
+
+impl StatusError {
+    fn code(error: &io::Error) -> u16 {
+        error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<StatusError>())
+            .map(|e| e.code)
+            .unwrap_or(500)
+    }
+}
+
+impl std::fmt::Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+fn status_error(code: u16, message: impl Into<String>) -> io::Error {
+    io::Error::new(
+        ErrorKind::Other,
+        StatusError {
+            code,
+            message: message.into(),
+        },
+    )
+}
+
+/// Methods a path below `/bundle/{id}` responds to, `None` if there is nothing there
+fn bundle_methods(resource: &[&str]) -> Option<&'static str> {
+    match resource {
+        [] => Some("POST, DELETE, PATCH"),
+        ["commit" | "rollback" | "activate"] | ["chunk", _] => Some("POST"),
+        ["files" | "logs" | "archive"] => Some("GET"),
+        _ => None,
+    }
+}
+
+/// Narrows down the listing through the query of `GET /`, e.g. `?domain=foo&state=failed`
+#[derive(Default)]
+struct ListFilter {
+    /// Substring the domain of a bundle has to contain, excluding failed ones
+    domain: Option<String>,
+    /// One of `prepared`, `active` or `failed`
+    state: Option<String>,
+    /// Whether prepared versions are listed, which older clients would mistake for served ones
+    prepared: bool,
+    limit: Option<usize>,
+    offset: usize,
+}
+
+impl ListFilter {
+    fn parse(query: &str) -> io::Result<Self> {
+        let mut filter = ListFilter::default();
+
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            let (key, value) = (key.as_ref(), value.as_ref());
+            let number = || {
+                value
+                    .parse::<usize>()
+                    .map_err(|_| status_error(400, format!("invalid {key} parameter")))
+            };
+
+            match key {
+                "domain" => filter.domain = Some(value.to_owned()),
+                "state" if matches!(value, "prepared" | "active" | "failed") => {
+                    filter.prepared |= value == "prepared";
+                    filter.state = Some(value.to_owned())
+                }
+                "state" => return Err(status_error(400, format!("unknown state '{value}'"))),
+                "prepared" => filter.prepared = !matches!(value, "0" | "false" | "no"),
+                "limit" => filter.limit = Some(number()?),
+                "offset" => filter.offset = number()?,
+                _ => {}
+            }
+        }
+
+        Ok(filter)
+    }
+
+    fn matches(&self, bundle: &Bundle) -> bool {
+        if matches!(bundle, Bundle::Prepared { .. }) && !self.prepared {
+            return false;
+        }
+
+        let domain = match (&self.domain, bundle.config()) {
+            (None, _) => true,
+            (Some(domain), Some(config)) => config.domain.contains(domain.as_str()),
+            (Some(_), None) => false,
+        };
+
+        domain
+            && self
+                .state
+                .as_deref()
+                .is_none_or(|state| state == bundle.state())
+    }
+
+    /// Filters a listing and selects a page of it, ordered by id so pages are stable
+    ///
+    /// Prepared versions stay in front of the current one of a bundle, as in the listing.
+    fn apply(
+        &self,
+        listing: impl Iterator<Item = (Ulid, Bundle)>,
+    ) -> impl Iterator<Item = (Ulid, Bundle)> {
+        let mut bundles = listing
+            .filter(|(_, bundle)| self.matches(bundle))
+            .collect::<Vec<_>>();
+
+        bundles.sort_by_key(|(id, bundle)| (*id, !matches!(bundle, Bundle::Prepared { .. })));
+
+        bundles
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+    }
+}
+
 pub struct Server {
     options: Options,
     manager: BundleManager,
+    /// Bundles included in the config Caddy last accepted
+    served: HashSet<Ulid>,
+    /// Responses of recent uploads by bundle and idempotency key, answered again on retries
+    recent: HashMap<(Ulid, String), (Instant, String)>,
+    /// Config Caddy last accepted, compared against the live one to detect drift
+    applied: Option<Value>,
+    /// Recent events by bundle, the oldest dropped beyond [`LOG_CAPACITY`] and [`LOG_BUNDLES`]
+    logs: HashMap<Ulid, VecDeque<LogEntry>>,
+    /// Whether all hosts currently answer with the maintenance page
+    maintenance: bool,
 }
 
 impl Server {
     pub fn new(options: Options) -> io::Result<Self> {
-        let storage = BundleStorage::new(options.storage.clone())?;
-        let manager = BundleManager::new(storage, Compressor::default());
-        let mut instance = Self { options, manager };
+        let storage = BundleStorage::new(options.storage.clone(), options.storage_sharded)?
+            .with_limits(options.unpack_limits);
+        let compressor = Compressor::default().with_verification(options.verify_compression);
+        let manager = BundleManager::new(storage, compressor, options.domains.clone())
+            .with_history_depth(options.history_depth)
+            .with_rate_limits(options.rate_limits)
+            .with_extra_handlers(options.extra_handlers.clone());
+        let mut instance = Self {
+            maintenance: options.maintenance,
+            options,
+            manager,
+            served: HashSet::new(),
+            recent: HashMap::new(),
+            applied: None,
+            logs: HashMap::new(),
+        };
 
-        instance.manager.load_all()?;
+        instance
+            .manager
+            .load_all(instance.options.load_concurrency)?;
         instance.reload_config()?;
         instance.reload_ingress()?;
 
         Ok(instance)
     }
 
-    fn reload_config(&self) -> io::Result<()> {
+    fn caddy_config(&self) -> CaddyConfig {
         let hosts = self.manager.hosts().collect::<Vec<_>>();
-        let config = CaddyConfig::new(
+
+        CaddyConfig::new(
             self.options.domains.clone(),
             hosts,
             self.options.caddy_dir.clone(),
             self.options.tls.clone(),
-        );
+            self.options.log_dir.clone(),
+            self.maintenance.then(|| Maintenance {
+                page: self.options.maintenance_page.clone(),
+            }),
+        )
+    }
+
+    fn reload_config(&mut self) -> io::Result<()> {
+        let config = self.caddy_config();
+        let included = self.manager.active().collect::<HashSet<_>>();
 
-        let mut result = Ok(());
-        for _ in 0..10 {
-            result = config
-                .apply(&self.options.caddy_endpoint)
-                .map_err(|e| io::Error::new(ErrorKind::Other, e));
+        const ATTEMPTS: usize = 10;
 
-            if result.is_ok() {
-                return Ok(());
+        let mut attempt = 1;
+
+        loop {
+            match config.apply(&self.options.caddy_endpoint) {
+                Ok(()) => {
+                    self.served = included;
+                    self.applied = Some(config.into());
+                    return Ok(());
+                }
+                // An invalid config will not become valid by retrying
+                Err(e @ ApplyError::Rejected { .. }) => {
+                    eprintln!("{e}");
+                    return Err(io::Error::other(e));
+                }
+                Err(e) if attempt == ATTEMPTS => {
+                    return Err(io::Error::other(format!(
+                        "giving up after {ATTEMPTS} attempts, {e}"
+                    )));
+                }
+                Err(e) => eprintln!("failed to apply config (attempt {attempt}/{ATTEMPTS}): {e}"),
             }
 
+            attempt += 1;
             sleep(Duration::from_millis(250));
         }
-
-        result
     }
 
     fn reload_ingress(&self) -> io::Result<()> {
@@ -124,55 +324,995 @@ spec:
         Ok(())
     }
 
-    pub fn listen(&mut self, port: u16) {
+    /// Address the API should be served on
+    pub fn bind(&self) -> SocketAddr {
+        self.options.bind
+    }
+
+    pub fn listen(&mut self, bind: SocketAddr) {
         use Method::*;
 
-        let server = tiny_http::Server::http(("0.0.0.0", port)).expect("failed to bind");
+        let server = tiny_http::Server::http(bind).expect("failed to bind");
+
+        let mut last_expiry = Instant::now();
+        let mut last_reconcile = Instant::now();
+
+        let timeout = self
+            .options
+            .reconcile_interval
+            .map_or(EXPIRY_INTERVAL, |interval| interval.min(EXPIRY_INTERVAL));
+
+        loop {
+            // Wake up periodically even without traffic so expired bundles are removed in time
+            if last_expiry.elapsed() >= EXPIRY_INTERVAL {
+                last_expiry = Instant::now();
 
-        for mut request in server.incoming_requests() {
-            let response = if *request.method() == Get {
-                Response::from_string(self.handle_get())
-            } else if let Some(Ok(id)) = request
-                .url()
-                .strip_prefix("/bundle/")
-                .map(Ulid::from_string)
+                if let Err(e) = self.expire() {
+                    eprintln!("failed to remove expired bundles: {e}");
+                }
+            }
+
+            if let Some(interval) = self.options.reconcile_interval {
+                if last_reconcile.elapsed() >= interval {
+                    last_reconcile = Instant::now();
+
+                    if let Err(e) = self.reconcile() {
+                        eprintln!("failed to reconcile caddy config: {e}");
+                    }
+                }
+            }
+
+            let mut request = match server.recv_timeout(timeout) {
+                Ok(Some(request)) => request,
+                Ok(None) => continue,
+                Err(e) => {
+                    eprintln!("failed to receive request: {e}");
+                    continue;
+                }
+            };
+
+            if !self.permitted(&request) {
+                let peer = request.remote_addr().map(|addr| addr.ip().to_string());
+                eprintln!(
+                    "rejected {} {} from {}",
+                    request.method(),
+                    request.url(),
+                    peer.as_deref().unwrap_or("unknown peer")
+                );
+                request
+                    .respond(Response::from_string("Forbidden").with_status_code(403))
+                    .ok();
+                continue;
+            }
+
+            let url = request.url().to_owned();
+            let (segments, query) = route(&url);
+            let method = request.method().clone();
+
+            let id = match segments.as_slice() {
+                ["bundle", id, ..] => match Ulid::from_string(id) {
+                    Ok(id) => Some(id),
+                    Err(e) => {
+                        let message = format!("invalid bundle id '{id}': {e}");
+                        request
+                            .respond(Response::from_string(message).with_status_code(400))
+                            .ok();
+                        continue;
+                    }
+                },
+                _ => None,
+            };
+
+            // Archives are streamed from disk instead of being buffered like other responses
+            if let (Get, Some(id), [_, _, "archive"]) = (&method, id, segments.as_slice()) {
+                self.handle_archive(request, id);
+                continue;
+            }
+
+            // Large listings are written line by line for clients which can parse them that way
+            if method == Get
+                && segments.is_empty()
+                && header(&request, "Accept").is_some_and(|accept| accept.contains(NDJSON))
             {
-                let result = match request.method() {
-                    Post => self.handle_post(&mut request, id),
-                    Delete => self.handle_delete(&mut request, id),
-                    _ => Ok("OK".into()),
+                let filter = match ListFilter::parse(query) {
+                    Ok(filter) => filter,
+                    Err(e) => {
+                        let response = Response::from_string(e.to_string())
+                            .with_status_code(StatusError::code(&e));
+                        request.respond(response).ok();
+                        continue;
+                    }
                 };
 
-                match result {
-                    Ok(payload) => Response::from_string(payload),
-                    Err(e) => Response::from_string(e.to_string()).with_status_code(500),
+                let lines = NdjsonReader::new(filter.apply(self.listing()));
+                let mut headers =
+                    vec![Header::from_bytes("Content-Type", NDJSON).expect("invalid header")];
+
+                // Caches have to tell the encoded and plain variants of the listing apart
+                if self.options.compress_responses {
+                    headers.push(
+                        Header::from_bytes("Vary", "Accept-Encoding").expect("invalid header"),
+                    );
+                }
+
+                let lines: Box<dyn Read> = if self.gzip_response(&request) {
+                    headers.push(
+                        Header::from_bytes("Content-Encoding", "gzip").expect("invalid header"),
+                    );
+                    Box::new(read::GzEncoder::new(lines, Compression::default()))
+                } else {
+                    Box::new(lines)
+                };
+
+                let response = Response::new(200.into(), headers, lines, None, None);
+
+                if let Err(e) = request.respond(response) {
+                    eprintln!("failed to send bundle list: {e}");
+                }
+
+                continue;
+            }
+
+            let result = match (&method, segments.as_slice(), id) {
+                (Get, [], _) => self.handle_get(query),
+                (Head, [], _) => Ok(String::new()),
+                (Post, ["prune"], _) => self.handle_prune(),
+                (_, ["prune"], _) => Err(status_error(405, "method not allowed")),
+                (Get, ["version"], _) => self.handle_version(),
+                (Get, ["maintenance"], _) => Ok(self.maintenance.to_string()),
+                (Post, ["maintenance"], _) => self.handle_maintenance(true),
+                (Delete, ["maintenance"], _) => self.handle_maintenance(false),
+                (_, ["maintenance"], _) => Err(status_error(405, "method not allowed")),
+                (Get, ["caddy-config"], _) => self.handle_caddy_config(),
+                (Get, ["history", domain], _) => self.handle_history(domain),
+                (_, [_, _, resource @ ..], Some(id)) => {
+                    let result = self.handle_bundle(&mut request, id, resource, query);
+
+                    if let Err(e) = &result {
+                        self.log(id, format!("{method} {url} failed: {e}"));
+                    }
+
+                    result
+                }
+                _ => Err(status_error(404, "not found")),
+            };
+
+            let response = match result {
+                Ok(payload) => Response::from_string(payload),
+                Err(e) if StatusError::code(&e) == 405 => {
+                    let methods = match (segments.as_slice(), id) {
+                        (["maintenance"], _) => Some("GET, POST, DELETE"),
+                        (["prune"], _) => Some("POST"),
+                        ([_, _, resource @ ..], Some(_)) => bundle_methods(resource),
+                        _ => None,
+                    };
+
+                    let response = Response::from_string(e.to_string()).with_status_code(405);
+
+                    match methods {
+                        Some(methods) => response.with_header(
+                            Header::from_bytes("Allow", methods).expect("invalid header"),
+                        ),
+                        None => response,
+                    }
                 }
-            } else {
-                Response::from_string("Not found").with_status_code(404)
+                Err(e) => {
+                    Response::from_string(e.to_string()).with_status_code(StatusError::code(&e))
+                }
+            };
+
+            let response = match self.gzip_response(&request) {
+                true => compress_response(response),
+                false => response,
             };
 
-            request.respond(response).ok();
+            // Advertises which encodings of upload bodies are understood (RFC 7694)
+            let accept = Header::from_bytes("Accept-Encoding", "gzip").expect("invalid header");
+            request.respond(response.with_header(accept)).ok();
+        }
+    }
+
+    /// Whether the response body may be compressed, which clients opt into per request
+    fn gzip_response(&self, request: &Request) -> bool {
+        self.options.compress_responses
+            && *request.method() != Method::Head
+            && header(request, "Accept-Encoding").is_some_and(|accept| {
+                accept.split(',').any(|coding| {
+                    let mut parts = coding.split(';').map(str::trim);
+                    let q = parts.find_map(|p| p.strip_prefix("q=")).unwrap_or("1");
+                    coding.trim().starts_with("gzip") && q.parse::<f32>().is_ok_and(|q| q > 0.0)
+                })
+            })
+    }
+
+    /// Routes requests below `/bundle/{id}` by their remaining path segments
+    fn handle_bundle(
+        &mut self,
+        request: &mut Request,
+        id: Ulid,
+        resource: &[&str],
+        query: &str,
+    ) -> io::Result<String> {
+        use Method::*;
+
+        match (request.method().clone(), resource) {
+            (Post, []) => self.handle_post(request, id),
+            (Delete, []) => self.handle_delete(request, id),
+            (Patch, []) => self.handle_patch(request, id),
+            (Post, ["commit"]) => self.handle_commit(request, id),
+            (Post, ["rollback"]) => self.handle_rollback(id),
+            (Post, ["activate"]) => self.handle_activate(request, id),
+            (Get, ["files"]) => self.handle_files(id),
+            (Get, ["logs"]) => self.handle_logs(id, query),
+            (Post, ["chunk", index]) => match index.parse() {
+                Ok(index) => self.handle_chunk(request, id, index),
+                Err(_) => Err(status_error(400, format!("invalid chunk index '{index}'"))),
+            },
+            _ if bundle_methods(resource).is_some() => Err(status_error(405, "method not allowed")),
+            _ => Err(status_error(404, "not found")),
+        }
+    }
+
+    /// Checks the peer against the allow-list, which only guards mutations unless told otherwise
+    fn permitted(&self, request: &Request) -> bool {
+        let allowed = match &self.options.allowed {
+            Some(allowed) => allowed,
+            None => return true,
+        };
+
+        let read_only = matches!(request.method(), Method::Get | Method::Head);
+
+        if read_only && !self.options.restrict_reads {
+            return true;
         }
+
+        request
+            .remote_addr()
+            .is_some_and(|peer| allowed.iter().any(|cidr| cidr.contains(peer.ip())))
+    }
+
+    fn handle_get(&self, query: &str) -> io::Result<String> {
+        let filter = ListFilter::parse(query)?;
+        let map = filter.apply(self.listing()).collect::<HashMap<_, _>>();
+        Ok(serde_json::to_string(&map)?)
+    }
+
+    /// All bundles along with whether they are served and how well they compressed
+    ///
+    /// Prepared versions come first, so the current one of a bundle wins when collected by id.
+    fn listing(&self) -> impl Iterator<Item = (Ulid, Bundle)> + '_ {
+        let current = self.manager.bundles().map(|(id, mut bundle)| {
+            if let Bundle::Active {
+                stats,
+                warning,
+                served,
+                ..
+            } = &mut bundle
+            {
+                *served = !self.maintenance && self.served.contains(&id);
+                *warning = self.degraded(stats);
+            }
+
+            (id, bundle)
+        });
+
+        self.manager.prepared().chain(current)
     }
 
-    fn handle_get(&self) -> String {
-        let map = self.manager.bundles().collect::<HashMap<_, _>>();
-        serde_json::to_string(&map).expect("failed to serialize bundles")
+    /// Warns about bundles which save less than the configured minimum through compression
+    fn degraded(&self, stats: &Statistics) -> Option<String> {
+        let min = self.options.min_savings?;
+        let savings = stats.savings() * 100.0;
+
+        (savings < min).then(|| {
+            format!("degraded: compression saves {savings:.2}%, expected at least {min:.2}%")
+        })
     }
 
     fn handle_post(&mut self, request: &mut Request, id: Ulid) -> io::Result<String> {
-        self.manager.storage.add(id, request.as_reader())?;
-        let bundle = self.manager.deploy(id)?;
+        let key = header(request, IDEMPOTENCY_HEADER).map(|key| (id, key));
+
+        if let Some(response) = self.replay(key.as_ref()) {
+            return Ok(response);
+        }
+
+        // Earlier responses no longer describe the bundle once another upload begins
+        self.forget_uploads(id);
+
+        let ttl = ttl(request)?;
+        let prepare = header(request, PREPARE_HEADER).is_some();
+        let redeploy = self.manager.config(id).is_some();
+        let depth = self.upload_depth(redeploy);
+        let storage = &self.manager.storage;
+
+        let mut reader: Box<dyn Read> = if gzip(request, "Content-Encoding")? {
+            storage.decode_gzip(request.as_reader())
+        } else {
+            Box::new(request.as_reader())
+        };
+
+        if prepare {
+            storage.add_prepared(id, &mut reader)?;
+        } else {
+            storage.add(id, &mut reader, depth)?;
+        }
+
+        drop(reader);
+
+        let response = if prepare {
+            self.manager.storage.set_prepared_ttl(id, ttl)?;
+            self.stage(id)?
+        } else {
+            self.deploy_upload(
+                id,
+                redeploy,
+                ttl,
+                header(request, FORCE_HEADER).is_some(),
+                header(request, OVERWRITE_HEADER).is_some(),
+                header(request, VERBOSE_HEADER).is_some(),
+            )?
+        };
+        self.remember(key, &response);
+
+        Ok(response)
+    }
+
+    /// Returns the response of an earlier upload with the same idempotency key
+    fn replay(&mut self, key: Option<&(Ulid, String)>) -> Option<String> {
+        self.recent
+            .retain(|_, (timestamp, _)| timestamp.elapsed() < IDEMPOTENCY_WINDOW);
+
+        key.and_then(|key| self.recent.get(key))
+            .map(|(_, response)| response.clone())
+    }
+
+    /// Drops the remembered upload responses of a bundle whose state changed
+    fn forget_uploads(&mut self, id: Ulid) {
+        self.recent.retain(|(bundle, _), _| *bundle != id);
+    }
+
+    fn remember(&mut self, key: Option<(Ulid, String)>, response: &str) {
+        if let Some(key) = key {
+            self.recent
+                .insert(key, (Instant::now(), response.to_owned()));
+        }
+    }
+
+    fn handle_chunk(
+        &mut self,
+        request: &mut Request,
+        id: Ulid,
+        index: usize,
+    ) -> io::Result<String> {
+        self.manager
+            .storage
+            .expire_chunks(self.options.chunk_expiry)?;
+
+        self.manager
+            .storage
+            .add_chunk(id, index, request.as_reader())?;
+
+        Ok("OK".into())
+    }
+
+    fn handle_commit(&mut self, request: &mut Request, id: Ulid) -> io::Result<String> {
+        let key = header(request, IDEMPOTENCY_HEADER).map(|key| (id, key));
+
+        if let Some(response) = self.replay(key.as_ref()) {
+            return Ok(response);
+        }
+
+        // Earlier responses no longer describe the bundle once another upload begins
+        self.forget_uploads(id);
+
+        let ttl = ttl(request)?;
+        let gzip = gzip(request, ARCHIVE_ENCODING_HEADER)?;
+        let prepare = header(request, PREPARE_HEADER).is_some();
+        let count: usize = serde_json::from_reader(request.as_reader())?;
+        let redeploy = self.manager.config(id).is_some();
+        let depth = self.upload_depth(redeploy);
+
+        self.manager
+            .storage
+            .commit_chunks(id, count, gzip, prepare, depth)?;
+
+        let response = if prepare {
+            self.manager.storage.set_prepared_ttl(id, ttl)?;
+            self.stage(id)?
+        } else {
+            self.deploy_upload(
+                id,
+                redeploy,
+                ttl,
+                header(request, FORCE_HEADER).is_some(),
+                header(request, OVERWRITE_HEADER).is_some(),
+                header(request, VERBOSE_HEADER).is_some(),
+            )?
+        };
+        self.remember(key, &response);
+
+        Ok(response)
+    }
+
+    fn schedule_expiry(&self, id: Ulid, ttl: Option<Duration>) -> io::Result<()> {
+        match ttl {
+            Some(ttl) => self.manager.storage.set_expiry(id, SystemTime::now() + ttl),
+            None => self.manager.storage.clear_expiry(id),
+        }
+    }
+
+    /// Prints an event about a bundle and keeps it for clients asking for its logs
+    fn log(&mut self, id: Ulid, message: impl Into<String>) {
+        let message = message.into();
+        println!("bundle {id}: {message}");
+
+        // Requests for made up ids are logged too, so the number of bundles has to be bounded
+        if !self.logs.contains_key(&id) && self.logs.len() >= LOG_BUNDLES {
+            let stalest = self
+                .logs
+                .iter()
+                .min_by_key(|(_, entries)| entries.back().map(|e| e.timestamp))
+                .map(|(id, _)| *id);
+
+            if let Some(stalest) = stalest {
+                self.logs.remove(&stalest);
+            }
+        }
+
+        let entries = self.logs.entry(id).or_default();
+        let sequence = entries.back().map(|e| e.sequence + 1).unwrap_or_default();
+
+        if entries.len() == LOG_CAPACITY {
+            entries.pop_front();
+        }
+
+        entries.push_back(LogEntry {
+            sequence,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            message,
+        });
+    }
+
+    /// Re-applies the config if Caddy lost it, e.g. after a restart or an external change
+    fn reconcile(&mut self) -> io::Result<()> {
+        let live = CaddyConfig::live(&self.options.caddy_endpoint).map_err(io::Error::other)?;
+
+        if self.applied.as_ref() == Some(&live) {
+            return Ok(());
+        }
+
+        println!("caddy config drifted, re-applying");
+        self.reload_config()?;
+        self.reload_ingress()
+    }
+
+    /// Removes all bundles whose time to live has passed
+    fn expire(&mut self) -> io::Result<()> {
+        let expired = self.manager.storage.expired(SystemTime::now())?;
+
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        for id in expired {
+            self.log(id, "expired, removing");
+            self.manager.storage.remove(id)?;
+            self.manager.remove(id);
+            self.forget_uploads(id);
+        }
+
+        self.reload_config()?;
+        self.reload_ingress()
+    }
+
+    /// Archives retained when storing an upload, at least the served one for redeploys so it
+    /// can be restored if the upload fails to deploy
+    fn upload_depth(&self, redeploy: bool) -> usize {
+        match redeploy {
+            true => self.manager.history_depth().max(1),
+            false => self.manager.history_depth(),
+        }
+    }
+
+    /// Deploys a freshly stored upload, undoing the upload if it can not be deployed
+    ///
+    /// New bundles are removed from storage so broken uploads do not pile up, while redeploys
+    /// restore the archive of the version which is still being served.
+    fn deploy_upload(
+        &mut self,
+        id: Ulid,
+        redeploy: bool,
+        ttl: Option<Duration>,
+        force: bool,
+        overwrite: bool,
+        verbose: bool,
+    ) -> io::Result<String> {
+        // Read up front, as undoing a failed upload may remove its config
+        let domain = self.manager.storage.metadata(id).ok().map(|c| c.domain);
+
+        let result = self
+            .check_overwrite(id, force || overwrite)
+            .and_then(|_| self.deploy(id, force, verbose, false));
+
+        if let Some(webhook) = &self.options.webhook {
+            webhook.notify(DeployEvent {
+                id,
+                domains: domain.into_iter().collect(),
+                stats: result
+                    .as_ref()
+                    .ok()
+                    .map(|deployment| deployment.stats.clone()),
+                error: result.as_ref().err().map(ToString::to_string),
+            });
+        }
+
+        let error = match result {
+            Ok(deployment) => {
+                let depth = self.manager.history_depth();
+                self.schedule_expiry(id, ttl)?;
+                self.manager.storage.trim(id, depth)?;
+                return Ok(serde_json::to_string(&deployment)?);
+            }
+            // The bundle is live despite routing failures, so its archive has to stay
+            Err(e) if StatusError::code(&e) == 502 => {
+                self.schedule_expiry(id, ttl)?;
+                return Err(e);
+            }
+            Err(e) => e,
+        };
+
+        let undone = match redeploy {
+            true => self.manager.storage.restore(id),
+            false => {
+                self.manager.remove(id);
+                self.manager.storage.remove(id)
+            }
+        };
+
+        match undone {
+            Ok(()) if redeploy => self.log(id, "upload failed, restored the served version"),
+            Ok(()) => self.log(id, "upload failed, removed it from storage"),
+            Err(e) => self.log(id, format!("upload failed and could not be undone: {e}")),
+        }
+
+        Err(error)
+    }
+
+    /// Refuses uploads which move a served bundle to another domain unless overwriting
+    ///
+    /// Storage is keyed by the id from `launch.json`, so a config copied between projects
+    /// would otherwise silently replace the deployment of the other project.
+    fn check_overwrite(&self, id: Ulid, overwrite: bool) -> io::Result<()> {
+        let previous = match self.manager.config(id) {
+            Some(previous) if !overwrite => previous,
+            _ => return Ok(()),
+        };
+
+        let domain = self.manager.storage.metadata(id)?.domain;
+
+        if domain == previous.domain {
+            return Ok(());
+        }
+
+        let change = DomainChange {
+            id,
+            served: previous.domain.clone(),
+            uploaded: domain,
+        };
+
+        Err(status_error(409, serde_json::to_string(&change)?))
+    }
+
+    /// Activates a stored bundle, replacing bundles holding its domain if `force` is set
+    ///
+    /// With `prepared` set, the prepared version of the bundle is activated instead.
+    fn deploy(
+        &mut self,
+        id: Ulid,
+        force: bool,
+        verbose: bool,
+        prepared: bool,
+    ) -> io::Result<Deployment> {
+        let mut replaced = Vec::new();
+
+        let stats = loop {
+            let result = match prepared {
+                true => self.manager.activate(id),
+                false => self.manager.deploy(id),
+            };
+
+            let error = match result {
+                Ok(stats) => break stats,
+                Err(e) => e,
+            };
+
+            match error
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<DomainConflict>())
+            {
+                Some(conflict) if force => {
+                    let message = format!("takes over {} from {}", conflict.existing, conflict.id);
+                    self.log(id, message);
+                    self.log(conflict.id, format!("replaced by {id}"));
+                    self.manager.storage.remove(conflict.id)?;
+                    self.manager.remove(conflict.id);
+                    self.forget_uploads(conflict.id);
+                    replaced.push(conflict.id);
+                }
+                Some(conflict) => return Err(status_error(409, serde_json::to_string(conflict)?)),
+                None if error.kind() == ErrorKind::NotFound && prepared => {
+                    return Err(status_error(404, error.to_string()))
+                }
+                None => return Err(error),
+            }
+        };
+
+        // The bundle is live in memory at this point, so routing failures are reported separately
+        if let Err(e) = self.reload_config() {
+            return Err(status_error(
+                502,
+                format!("bundle {id} was deployed but routing could not be updated: {e}"),
+            ));
+        }
+
+        self.reload_ingress()?;
+        let action = if prepared { "activated" } else { "deployed" };
+        self.log(id, format!("{action}, {} bytes", stats.size));
+
+        let mut urls = Vec::new();
+
+        if let Some(config) = self.manager.config(id) {
+            urls.push(config.url());
+
+            let entry = HistoryEntry {
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default(),
+                id,
+                stats: stats.clone(),
+            };
+
+            if let Err(e) = self.manager.storage.append_history(&config.domain, &entry) {
+                eprintln!("failed to record deployment history for {id}: {e}");
+            }
+        }
+
+        let route = match verbose {
+            true => self.manager.host(id).map(Into::into),
+            false => None,
+        };
+
+        Ok(Deployment {
+            urls,
+            stats,
+            replaced,
+            route,
+        })
+    }
+
+    /// Unpacks and compresses the prepared version of a bundle, leaving the served one alone
+    fn stage(&mut self, id: Ulid) -> io::Result<String> {
+        let stats = self.manager.stage(id)?;
+        self.log(id, format!("prepared, {} bytes", stats.size));
+
+        let urls = self
+            .manager
+            .storage
+            .prepared_metadata(id)
+            .map(|config| vec![config.url()])
+            .unwrap_or_default();
+
+        Ok(serde_json::to_string(&Deployment {
+            urls,
+            stats,
+            replaced: Vec::new(),
+            route: None,
+        })?)
+    }
+
+    fn handle_activate(&mut self, request: &mut Request, id: Ulid) -> io::Result<String> {
+        self.forget_uploads(id);
+
+        // Read up front, as activating consumes it along with the prepared archive
+        let ttl = self.manager.storage.prepared_ttl(id)?;
+
+        let deployment = self.deploy(
+            id,
+            header(request, FORCE_HEADER).is_some(),
+            header(request, VERBOSE_HEADER).is_some(),
+            true,
+        );
+
+        let deployment = match deployment {
+            Err(e) if StatusError::code(&e) != 502 => return Err(e),
+            result => {
+                self.schedule_expiry(id, ttl)?;
+                result?
+            }
+        };
+
+        Ok(serde_json::to_string(&deployment)?)
+    }
+
+    fn handle_archive(&self, request: Request, id: Ulid) {
+        let result = match self.manager.storage.open(id) {
+            Ok(file) => {
+                let header = Header::from_bytes("Content-Type", "application/x-tar")
+                    .expect("invalid header");
+                request.respond(Response::from_file(file).with_header(header))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                request.respond(Response::from_string("Not found").with_status_code(404))
+            }
+            Err(e) => request.respond(Response::from_string(e.to_string()).with_status_code(500)),
+        };
+
+        if let Err(e) = result {
+            eprintln!("failed to send archive of {id}: {e}");
+        }
+    }
+
+    /// Recent events of a bundle, starting at the sequence number in the `since` query parameter
+    fn handle_logs(&self, id: Ulid, query: &str) -> io::Result<String> {
+        let since = query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("since="))
+            .map(|since| {
+                since
+                    .parse::<u64>()
+                    .map_err(|_| status_error(400, "invalid since parameter"))
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        let entries = self
+            .logs
+            .get(&id)
+            .into_iter()
+            .flatten()
+            .filter(|entry| entry.sequence >= since)
+            .collect::<Vec<_>>();
+
+        Ok(serde_json::to_string(&entries)?)
+    }
+
+    fn handle_files(&self, id: Ulid) -> io::Result<String> {
+        let files = self.manager.files(id).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => status_error(404, e.to_string()),
+            _ => e,
+        })?;
+
+        Ok(serde_json::to_string(&files)?)
+    }
+
+    fn handle_version(&self) -> io::Result<String> {
+        let info = VersionInfo {
+            version: env!("CARGO_PKG_VERSION").into(),
+            commit: option_env!("LAUNCH_GIT_COMMIT").map(Into::into),
+            algorithms: self.manager.algorithms(),
+        };
+
+        Ok(serde_json::to_string(&info)?)
+    }
+
+    /// Renders the config which would be applied to Caddy, without any credentials
+    fn handle_caddy_config(&self) -> io::Result<String> {
+        let mut config: Value = self.caddy_config().into();
+        redact(&mut config);
+        Ok(serde_json::to_string_pretty(&config)?)
+    }
+
+    /// Switches all hosts to the maintenance page or back to their regular routes
+    fn handle_maintenance(&mut self, enabled: bool) -> io::Result<String> {
+        if self.maintenance != enabled {
+            self.maintenance = enabled;
+            println!("maintenance mode {}", if enabled { "on" } else { "off" });
+            self.reload_config()?;
+        }
+
+        Ok(enabled.to_string())
+    }
+
+    fn handle_history(&self, domain: &str) -> io::Result<String> {
+        let history = self.manager.storage.history(domain)?;
+        Ok(serde_json::to_string(&history)?)
+    }
+
+    fn handle_prune(&mut self) -> io::Result<String> {
+        let removed = self.manager.prune()?;
+        Ok(serde_json::to_string(&removed)?)
+    }
+
+    fn handle_rollback(&mut self, id: Ulid) -> io::Result<String> {
+        self.forget_uploads(id);
+
+        let stats = self.manager.rollback(id).map_err(|e| {
+            let conflict = e.get_ref().and_then(|e| e.downcast_ref::<DomainConflict>());
+
+            match (conflict, e.kind()) {
+                (Some(conflict), _) => {
+                    status_error(409, serde_json::to_string(conflict).unwrap_or_default())
+                }
+                (None, ErrorKind::NotFound) => status_error(404, e.to_string()),
+                (None, _) => e,
+            }
+        })?;
+
+        self.log(id, "rolled back to the previous version");
+        self.reload_config()?;
+        self.reload_ingress()?;
+
+        Ok(serde_json::to_string(&Deployment {
+            urls: self
+                .manager
+                .config(id)
+                .map(|c| c.url())
+                .into_iter()
+                .collect(),
+            stats,
+            replaced: Vec::new(),
+            route: None,
+        })?)
+    }
+
+    fn handle_patch(&mut self, request: &mut Request, id: Ulid) -> io::Result<String> {
+        let changes = serde_json::from_reader(request.as_reader())
+            .map_err(|e| status_error(400, format!("invalid config changes: {e}")))?;
+
+        let config = self.manager.update(id, changes).map_err(|e| {
+            let conflict = e.get_ref().and_then(|e| e.downcast_ref::<DomainConflict>());
+
+            match (conflict, e.kind()) {
+                (Some(conflict), _) => {
+                    status_error(409, serde_json::to_string(conflict).unwrap_or_default())
+                }
+                (None, ErrorKind::NotFound) => status_error(404, e.to_string()),
+                (None, ErrorKind::InvalidInput) => status_error(400, e.to_string()),
+                (None, _) => e,
+            }
+        })?;
+
+        self.log(id, "config updated");
         self.reload_config()?;
         self.reload_ingress()?;
-        Ok(serde_json::to_string(&bundle)?)
+
+        Ok(serde_json::to_string(&config)?)
     }
 
     fn handle_delete(&mut self, _request: &mut Request, id: Ulid) -> io::Result<String> {
         self.manager.storage.remove(id)?;
         self.manager.remove(id);
+        self.forget_uploads(id);
+        self.log(id, "deleted");
         self.reload_config()?;
         self.reload_ingress()?;
         Ok("Deleted".into())
     }
 }
+
+/// Serialises list entries one line at a time as they are read
+struct NdjsonReader {
+    entries: std::vec::IntoIter<ListEntry>,
+    line: io::Cursor<Vec<u8>>,
+}
+
+impl NdjsonReader {
+    fn new(entries: impl Iterator<Item = (Ulid, Bundle)>) -> Self {
+        let entries = entries
+            .map(|(id, bundle)| ListEntry { id, bundle })
+            .collect::<Vec<_>>();
+
+        Self {
+            entries: entries.into_iter(),
+            line: io::Cursor::new(Vec::new()),
+        }
+    }
+}
+
+impl Read for NdjsonReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.line.read(buf)?;
+
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+
+            let Some(entry) = self.entries.next() else {
+                return Ok(0);
+            };
+
+            let mut line = serde_json::to_vec(&entry)?;
+            line.push(b'\n');
+            self.line = io::Cursor::new(line);
+        }
+    }
+}
+
+/// Splits a request URL into its path segments and query
+///
+/// Empty segments are dropped, so trailing or doubled slashes do not change the route.
+fn route(url: &str) -> (Vec<&str>, &str) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let segments = path.split('/').filter(|segment| !segment.is_empty());
+
+    (segments.collect(), query)
+}
+
+fn header(request: &Request, name: &'static str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str().trim().to_owned())
+}
+
+/// Replaces the body of a buffered response with its gzip encoding, unless it is tiny
+fn compress_response(response: Response<io::Cursor<Vec<u8>>>) -> Response<io::Cursor<Vec<u8>>> {
+    if response.data_length().unwrap_or_default() < GZIP_THRESHOLD {
+        return response;
+    }
+
+    let status = response.status_code();
+    let mut headers = response.headers().to_vec();
+    let body = response.into_reader().into_inner();
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let body = match encoder.write_all(&body).and_then(|_| encoder.finish()) {
+        Ok(compressed) => {
+            headers.push(Header::from_bytes("Content-Encoding", "gzip").expect("invalid header"));
+            headers.push(Header::from_bytes("Vary", "Accept-Encoding").expect("invalid header"));
+            compressed
+        }
+        Err(_) => body,
+    };
+
+    let length = body.len();
+    Response::new(status, headers, io::Cursor::new(body), Some(length), None)
+}
+
+/// Whether the body of an upload is gzip encoded, rejecting encodings the server lacks
+///
+/// The encoding is read from the header `name`, as chunked uploads carry it on their commit.
+fn gzip(request: &Request, name: &'static str) -> io::Result<bool> {
+    match header(request, name).as_deref() {
+        None | Some("identity") => Ok(false),
+        Some("gzip") => Ok(true),
+        Some(other) => Err(status_error(
+            415,
+            format!("unsupported content encoding '{other}'"),
+        )),
+    }
+}
+
+/// Reads the optional time to live in seconds the client attached to an upload
+fn ttl(request: &Request) -> io::Result<Option<Duration>> {
+    header(request, TTL_HEADER)
+        .map(|ttl| {
+            ttl.parse()
+                .map(Duration::from_secs)
+                .map_err(|_| status_error(400, format!("invalid {TTL_HEADER} header")))
+        })
+        .transpose()
+}
+
+/// Blanks out secrets like the DNS provider token before a config leaves the server
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if key == "api_token" {
+                    *value = Value::String("<redacted>".into());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
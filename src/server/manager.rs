@@ -1,48 +1,262 @@
-use super::{caddy::HostConfig, compressor::Compressor, storage::BundleStorage, Statistics};
-use crate::{shared::Bundle, BundleConfig};
+use super::{
+    caddy::HostConfig,
+    compressor::{Algorithm, Compressor, Digests, Previous, Stripped},
+    storage::BundleStorage,
+    Statistics,
+};
+use crate::{
+    shared::{Bundle, DomainConflict, ManifestEntry},
+    BundleConfig,
+};
+use serde_json::{Map, Value};
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, VecDeque},
+    fs,
     io::{self, ErrorKind},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Mutex,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
 };
 use temp_dir::TempDir;
 use ulid::Ulid;
+use walkdir::WalkDir;
 
 #[derive(Debug, Clone)]
 pub struct ActiveBundle {
     pub root: TempDir,
     pub config: BundleConfig,
     pub stats: Statistics,
+    /// Content digests of compressed files, used to skip unchanged files on redeploy
+    pub digests: Digests,
+    /// Files only served through their sidecars, see [`BundleConfig::strip_originals`]
+    pub stripped: Stripped,
 }
 
 #[derive(Debug)]
 pub enum BundleStatus {
-    Active(ActiveBundle),
+    Active(Box<ActiveBundle>),
     Failed(String),
 }
 
 pub struct BundleManager {
     bundles: HashMap<Ulid, BundleStatus>,
+    /// Bundles replaced by redeploys, most recent first, kept around for instant rollbacks
+    previous: HashMap<Ulid, VecDeque<ActiveBundle>>,
+    /// Number of replaced versions kept per bundle
+    history_depth: usize,
+    /// Whether bundles may set a rate limit, which Caddy only supports with an extra module
+    rate_limits: bool,
+    /// Caddy handlers allowed in the raw routes of bundles, none by default
+    extra_handlers: Vec<String>,
+    /// Uploads which are unpacked and compressed but not served yet, with the time they were
+    /// prepared at. They are kept apart as a bundle may be live while its next version waits.
+    prepared: HashMap<Ulid, (u64, ActiveBundle)>,
 
     pub storage: BundleStorage,
     compressor: Compressor,
+
+    /// Host patterns this instance is allowed to serve
+    domains: Vec<String>,
 }
 
 impl BundleManager {
-    pub fn new(storage: BundleStorage, compressor: Compressor) -> Self {
+    pub fn new(storage: BundleStorage, compressor: Compressor, domains: Vec<String>) -> Self {
         Self {
             bundles: HashMap::new(),
+            previous: HashMap::new(),
+            history_depth: 0,
+            rate_limits: false,
+            extra_handlers: Vec::new(),
+            prepared: HashMap::new(),
             storage,
             compressor,
+            domains,
         }
     }
 
+    pub fn with_history_depth(mut self, depth: usize) -> Self {
+        self.history_depth = depth;
+        self
+    }
+
+    pub fn with_rate_limits(mut self, enabled: bool) -> Self {
+        self.rate_limits = enabled;
+        self
+    }
+
+    pub fn with_extra_handlers(mut self, handlers: Vec<String>) -> Self {
+        self.extra_handlers = handlers;
+        self
+    }
+
+    pub fn history_depth(&self) -> usize {
+        self.history_depth
+    }
+
+    pub fn algorithms(&self) -> Vec<Algorithm> {
+        self.compressor.algorithms()
+    }
+
     pub fn bundles(&self) -> impl Iterator<Item = (Ulid, Bundle)> + '_ {
         self.bundles.iter().map(|(id, b)| (*id, Bundle::from(b)))
     }
 
-    pub fn load_all(&mut self) -> io::Result<()> {
-        for id in self.storage.enumerate()? {
-            if let Err(e) = self.deploy(id) {
+    /// Bundles waiting to be activated
+    pub fn prepared(&self) -> impl Iterator<Item = (Ulid, Bundle)> + '_ {
+        self.prepared.iter().map(|(id, (prepared, bundle))| {
+            (
+                *id,
+                Bundle::Prepared {
+                    config: Box::new(bundle.config.clone()),
+                    stats: bundle.stats.clone(),
+                    prepared: *prepared,
+                },
+            )
+        })
+    }
+
+    /// Ids of all bundles which are currently active
+    pub fn active(&self) -> impl Iterator<Item = Ulid> + '_ {
+        self.bundles
+            .iter()
+            .filter(|(_, status)| matches!(status, BundleStatus::Active(_)))
+            .map(|(id, _)| *id)
+    }
+
+    pub fn config(&self, id: Ulid) -> Option<&BundleConfig> {
+        match self.bundles.get(&id) {
+            Some(BundleStatus::Active(bundle)) => Some(&bundle.config),
+            _ => None,
+        }
+    }
+
+    /// Lists the files served for an active bundle, attaching sidecars to their originals
+    pub fn files(&self, id: Ulid) -> io::Result<Vec<ManifestEntry>> {
+        let bundle = match self.bundles.get(&id) {
+            Some(BundleStatus::Active(bundle)) => bundle,
+            _ => return Err(io::Error::new(ErrorKind::NotFound, "bundle is not active")),
+        };
+
+        let root = bundle.root.path();
+        let algorithms = self.compressor.algorithms_for(&bundle.config);
+        let mut entries = Vec::new();
+
+        for entry in WalkDir::new(root).sort_by_file_name() {
+            let entry = entry?;
+
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let path = entry.path();
+
+            if self.compressor.is_sidecar(path) {
+                continue;
+            }
+
+            let relative = path.strip_prefix(root).unwrap_or(path);
+            let compressed = algorithms
+                .iter()
+                .filter_map(|algorithm| {
+                    let sidecar = self.compressor.sidecar_path(root, relative, *algorithm);
+                    let metadata = fs::metadata(sidecar).ok().filter(|m| m.is_file())?;
+                    Some((*algorithm, metadata.len()))
+                })
+                .collect::<HashMap<_, _>>();
+
+            let size = match bundle.stripped.get(relative) {
+                Some(size) => *size,
+                None => entry.metadata()?.len(),
+            };
+
+            entries.push(ManifestEntry {
+                path: relative.to_string_lossy().replace('\\', "/"),
+                size,
+                encodings: algorithms
+                    .iter()
+                    .copied()
+                    .filter(|algorithm| compressed.contains_key(algorithm))
+                    .collect(),
+                compressed,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// Deploys all stored bundles, unpacking and compressing up to `concurrency` at once
+    ///
+    /// Prepared archives are unpacked and compressed as well, so they are listed again and
+    /// can be activated right away.
+    pub fn load_all(&mut self, concurrency: usize) -> io::Result<()> {
+        let jobs = self
+            .storage
+            .enumerate()?
+            .into_iter()
+            .map(|id| (id, None))
+            .chain(
+                self.storage
+                    .enumerate_prepared()?
+                    .into_iter()
+                    .map(|(id, timestamp)| (id, Some(timestamp))),
+            )
+            .collect::<Vec<_>>();
+
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(Vec::with_capacity(jobs.len()));
+
+        thread::scope(|scope| {
+            for _ in 0..concurrency.max(1).min(jobs.len()) {
+                scope.spawn(|| {
+                    while let Some((id, prepared)) = jobs.get(next.fetch_add(1, Ordering::Relaxed))
+                    {
+                        let result = match prepared {
+                            Some(_) => self.storage.prepared_metadata(*id).and_then(|config| {
+                                self.verify_config(&config)?;
+                                self.prepare(*id, config, true)
+                            }),
+                            None => self
+                                .storage
+                                .metadata(*id)
+                                .and_then(|config| self.prepare(*id, config, false)),
+                        };
+
+                        results
+                            .lock()
+                            .expect("bundle loader panicked")
+                            .push((*id, *prepared, result));
+                    }
+                });
+            }
+        });
+
+        let mut results = results.into_inner().expect("bundle loader panicked");
+        results.sort_by_key(|(id, _, _)| *id);
+
+        // Domain conflicts are checked one by one against the bundles activated so far
+        for (id, prepared, result) in results {
+            if let Some(timestamp) = prepared {
+                match result {
+                    Ok(bundle) => {
+                        self.prepared.insert(id, (timestamp, bundle));
+                    }
+                    Err(e) => eprintln!("failed to load prepared version of {id}: {e}"),
+                }
+
+                continue;
+            }
+
+            let result = result.and_then(|bundle| {
+                self.verify_bundle(id, &bundle.config)?;
+                self.bundles
+                    .insert(id, BundleStatus::Active(Box::new(bundle)));
+                Ok(())
+            });
+
+            if let Err(e) = result {
                 self.bundles.insert(id, BundleStatus::Failed(e.to_string()));
             }
         }
@@ -50,71 +264,381 @@ impl BundleManager {
         Ok(())
     }
 
+    /// Activates the stored archive of a bundle, retaining the version it replaces
+    ///
+    /// Retained versions mirror the archives retained in storage. Whenever that can not be
+    /// guaranteed, they are dropped from memory and rollbacks fall back to storage.
     pub fn deploy(&mut self, id: Ulid) -> io::Result<Statistics> {
-        let config = self.storage.metadata(id)?;
-        let root = TempDir::with_prefix("launch-")?;
-        let path = root.path();
+        let bundle = match self.load(id) {
+            Ok(bundle) => bundle,
+            Err(e) => {
+                self.previous.remove(&id);
+                return Err(e);
+            }
+        };
+
+        Ok(self.replace(id, bundle))
+    }
+
+    /// Serves a bundle in place of its current version, which is retained for rollbacks
+    fn replace(&mut self, id: Ulid, bundle: ActiveBundle) -> Statistics {
+        let stats = bundle.stats.clone();
+        let replaced = self
+            .bundles
+            .insert(id, BundleStatus::Active(Box::new(bundle)));
+
+        match replaced {
+            Some(BundleStatus::Active(replaced)) if self.history_depth > 0 => {
+                let previous = self.previous.entry(id).or_default();
+                previous.push_front(*replaced);
+                previous.truncate(self.history_depth);
+            }
+            _ => {
+                self.previous.remove(&id);
+            }
+        }
+
+        stats
+    }
+
+    /// Unpacks and compresses the prepared archive of a bundle without serving it
+    ///
+    /// Only the config itself is verified, domain conflicts are checked once it is activated.
+    pub fn stage(&mut self, id: Ulid) -> io::Result<Statistics> {
+        let config = self.storage.prepared_metadata(id)?;
+        self.verify_config(&config)?;
+
+        let bundle = self.prepare(id, config, true)?;
+        let stats = bundle.stats.clone();
+        self.prepared.insert(id, (now(), bundle));
+
+        Ok(stats)
+    }
+
+    /// Serves the prepared version of a bundle in place of the current one
+    ///
+    /// Prepared versions which are not held in memory (e.g. as they failed to load) are
+    /// unpacked and compressed from storage first.
+    pub fn activate(&mut self, id: Ulid) -> io::Result<Statistics> {
+        let (timestamp, bundle) = match self.prepared.remove(&id) {
+            Some(prepared) => prepared,
+            None => {
+                let config = self.storage.prepared_metadata(id)?;
+                self.verify_config(&config)?;
+                (now(), self.prepare(id, config, true)?)
+            }
+        };
+
+        let activated = self
+            .verify_bundle(id, &bundle.config)
+            .and_then(|_| self.storage.activate(id, self.history_depth));
+
+        match activated {
+            Ok(()) => Ok(self.replace(id, bundle)),
+            Err(e) => {
+                // Keeps the prepared version around so activation can be retried
+                self.prepared.insert(id, (timestamp, bundle));
+                Err(e)
+            }
+        }
+    }
 
+    /// Verifies, unpacks and compresses the stored archive of a bundle
+    fn load(&self, id: Ulid) -> io::Result<ActiveBundle> {
+        let config = self.storage.metadata(id)?;
         self.verify_bundle(id, &config)?;
+        self.prepare(id, config, false)
+    }
 
-        self.storage.unpack(id, path)?;
-        let stats = self.compressor.compress(path, &config.compress)?;
+    /// Reactivates the version of a bundle which the last redeploy replaced
+    ///
+    /// Versions still held in memory are swapped in without unpacking them again, older ones
+    /// retained only in storage (e.g. after a restart) are unpacked and compressed as usual.
+    pub fn rollback(&mut self, id: Ulid) -> io::Result<Statistics> {
+        let retained = self
+            .previous
+            .get(&id)
+            .and_then(|previous| previous.front())
+            .map(|bundle| bundle.config.clone());
 
-        let bundle = ActiveBundle {
-            root,
-            config,
-            stats: stats.clone(),
+        let bundle = match retained {
+            Some(config) => {
+                self.verify_bundle(id, &config)?;
+                self.storage.restore(id)?;
+                self.previous
+                    .get_mut(&id)
+                    .and_then(VecDeque::pop_front)
+                    .expect("retained bundle vanished")
+            }
+            None => {
+                self.storage.restore(id)?;
+                self.load(id)?
+            }
         };
 
-        self.bundles.insert(id, BundleStatus::Active(bundle));
+        let stats = bundle.stats.clone();
+        self.bundles
+            .insert(id, BundleStatus::Active(Box::new(bundle)));
 
         Ok(stats)
     }
 
+    /// Changes the config of an active bundle without unpacking it again
+    ///
+    /// Top-level fields of `changes` replace those of the current config. Fields which
+    /// affect the precompressed files can only be changed through a redeploy.
+    pub fn update(&mut self, id: Ulid, changes: Map<String, Value>) -> io::Result<BundleConfig> {
+        let current = self
+            .config(id)
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "bundle is not active"))?;
+
+        let mut value = serde_json::to_value(current)?;
+
+        if let Value::Object(fields) = &mut value {
+            fields.extend(changes);
+        }
+
+        let config: BundleConfig = serde_json::from_value(value)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+
+        if config.compress != current.compress
+            || config.brotli != current.brotli
+            || config.profile != current.profile
+            || config.strip_originals != current.strip_originals
+            || config.algorithms != current.algorithms
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "changing the compression requires a redeploy",
+            ));
+        }
+
+        self.verify_bundle(id, &config)?;
+        self.storage.set_metadata(id, &config)?;
+
+        if let Some(BundleStatus::Active(bundle)) = self.bundles.get_mut(&id) {
+            bundle.config = config.clone();
+        }
+
+        Ok(config)
+    }
+
+    /// Unpacks and compresses a bundle without activating it, reading its prepared archive
+    /// instead of the current one if `prepared` is set
+    fn prepare(&self, id: Ulid, config: BundleConfig, prepared: bool) -> io::Result<ActiveBundle> {
+        let root = TempDir::with_prefix("launch-")?;
+        let path = root.path();
+
+        let previous = match self.bundles.get(&id) {
+            Some(BundleStatus::Active(bundle))
+                if bundle.config.brotli == config.brotli
+                    && bundle.config.profile == config.profile =>
+            {
+                Some(Previous {
+                    root: bundle.root.path(),
+                    digests: &bundle.digests,
+                })
+            }
+            _ => None,
+        };
+
+        // Files are compressed while the rest of the archive is still being unpacked
+        let (stats, digests, stripped) = thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
+            let compression = scope.spawn(|| {
+                self.compressor
+                    .compress_stream(path, &config, previous, receiver)
+            });
+
+            let on_file = |file| {
+                sender.send(file).ok();
+            };
+            let unpacked = match prepared {
+                true => self.storage.unpack_prepared_with(id, path, on_file),
+                false => self.storage.unpack_with(id, path, on_file),
+            };
+            drop(sender);
+
+            let compressed = compression.join().expect("compression panicked");
+            unpacked.and(compressed)
+        })?;
+
+        Ok(ActiveBundle {
+            root,
+            config,
+            stats,
+            digests,
+            stripped,
+        })
+    }
+
     fn verify_bundle(&self, id: Ulid, config: &BundleConfig) -> io::Result<()> {
-        // TODO Verify that domain is allowed
+        self.verify_config(config)?;
 
-        let active_domains = self
+        let conflict = self
             .bundles
             .iter()
             .filter(|(i, _)| **i != id)
-            .filter_map(|(_, status)| match status {
-                BundleStatus::Active(bundle) => Some(&bundle.config.domain),
+            .filter_map(|(i, status)| match status {
+                BundleStatus::Active(bundle) => Some((i, &bundle.config)),
                 _ => None,
             })
-            .collect::<Vec<_>>();
+            // Bundles on the same domain may coexist as long as they are mounted at different paths
+            .filter(|(_, other)| other.path_prefix() == config.path_prefix())
+            .map(|(i, other)| (i, &other.domain))
+            .find(|(_, domain)| hosts_overlap(domain, &config.domain));
+
+        match conflict {
+            Some((other, domain)) => Err(io::Error::other(DomainConflict {
+                domain: config.domain.clone(),
+                existing: domain.clone(),
+                id: *other,
+            })),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks a config on its own, regardless of the other bundles
+    fn verify_config(&self, config: &BundleConfig) -> io::Result<()> {
+        config
+            .validate()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
 
-        if active_domains.contains(&&config.domain) {
+        if config.rate_limit.is_some() && !self.rate_limits {
             return Err(io::Error::new(
-                ErrorKind::Other,
-                "domain already in use by another bundle",
+                ErrorKind::InvalidInput,
+                "rate_limit is not supported by this instance",
             ));
         }
 
+        if let Some(routes) = &config.caddy_extra {
+            if self.extra_handlers.is_empty() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "caddy_extra is not supported by this instance",
+                ));
+            }
+
+            let mut handlers = Vec::new();
+            collect_handlers(routes, &mut handlers);
+
+            if let Some(handler) = handlers
+                .into_iter()
+                .find(|handler| !self.extra_handlers.iter().any(|allowed| allowed == handler))
+            {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("caddy_extra handler {handler} is not allowed by this instance"),
+                ));
+            }
+        }
+
+        if !self
+            .domains
+            .iter()
+            .any(|allowed| host_matches(allowed, &config.domain))
+        {
+            return Err(io::Error::other(format!(
+                "domain {} is not served by this instance",
+                config.domain
+            )));
+        }
+
         Ok(())
     }
 
     pub fn remove(&mut self, id: Ulid) {
         self.bundles.remove(&id);
+        self.previous.remove(&id);
+        self.prepared.remove(&id);
+    }
+
+    /// Removes all failed bundles from storage and memory, returning how many were dropped
+    pub fn prune(&mut self) -> io::Result<usize> {
+        let failed = self
+            .bundles
+            .iter()
+            .filter(|(_, status)| matches!(status, BundleStatus::Failed(_)))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in failed.iter() {
+            self.storage.remove(*id)?;
+            self.remove(*id);
+        }
+
+        Ok(failed.len())
     }
 
     pub fn hosts(&self) -> impl Iterator<Item = HostConfig> + '_ {
-        self.bundles.iter().filter_map(|(_, status)| match status {
+        self.bundles.keys().filter_map(|id| self.host(*id))
+    }
+
+    /// Caddy host config of an active bundle
+    pub fn host(&self, id: Ulid) -> Option<HostConfig> {
+        match self.bundles.get(&id)? {
             BundleStatus::Active(bundle) => Some(HostConfig::new(
                 vec![bundle.config.domain.clone()],
                 bundle.root.path().to_path_buf(),
-                self.compressor.algorithms(),
-                bundle.config.fallback.clone(),
+                self.compressor.algorithms_for(&bundle.config),
+                &bundle.config,
+                &bundle.stripped,
             )),
             _ => None,
-        })
+        }
     }
 
-    pub fn domains(&self) -> impl Iterator<Item = String> + '_ {
-        self.bundles.iter().filter_map(|(_, status)| match status {
-            BundleStatus::Active(bundle) => Some(bundle.config.domain.clone()),
-            _ => None,
-        })
+    /// Distinct domains of all active bundles
+    pub fn domains(&self) -> impl Iterator<Item = String> {
+        self.bundles
+            .iter()
+            .filter_map(|(_, status)| match status {
+                BundleStatus::Active(bundle) => Some(bundle.config.domain.clone()),
+                _ => None,
+            })
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Whether the host is matched by the pattern, following Caddy's single-label wildcard semantics
+fn host_matches(pattern: &str, host: &str) -> bool {
+    if pattern.eq_ignore_ascii_case(host) {
+        return true;
+    }
+
+    match (pattern.strip_prefix("*."), host.split_once('.')) {
+        (Some(parent), Some((label, rest))) => {
+            !label.is_empty() && label != "*" && parent.eq_ignore_ascii_case(rest)
+        }
+        _ => false,
+    }
+}
+
+/// Whether requests for one host could end up being routed to the other
+fn hosts_overlap(a: &str, b: &str) -> bool {
+    host_matches(a, b) || host_matches(b, a)
+}
+
+/// Names of all Caddy handlers used within raw routes, including nested subroutes
+fn collect_handlers<'a>(value: &'a Value, handlers: &mut Vec<&'a str>) {
+    match value {
+        Value::Object(object) => {
+            if let Some(Value::String(handler)) = object.get("handler") {
+                handlers.push(handler);
+            }
+
+            object.values().for_each(|v| collect_handlers(v, handlers));
+        }
+        Value::Array(values) => values.iter().for_each(|v| collect_handlers(v, handlers)),
+        _ => {}
     }
 }
 
@@ -122,8 +646,10 @@ impl From<&BundleStatus> for Bundle {
     fn from(value: &BundleStatus) -> Self {
         match value {
             BundleStatus::Active(b) => Self::Active {
-                config: b.config.clone(),
+                config: Box::new(b.config.clone()),
                 stats: b.stats.clone(),
+                warning: None,
+                served: false,
             },
             BundleStatus::Failed(e) => Self::Failed { error: e.clone() },
         }
@@ -1,32 +1,97 @@
 mod caddy;
+mod cidr;
 mod compressor;
 mod http;
 mod manager;
 mod storage;
+mod webhook;
 
-use caddy::TlsConfig;
+use caddy::{CaEndpoint, TlsConfig};
+use cidr::Cidr;
 use http::Server;
-use std::path::PathBuf;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+use storage::UnpackLimits;
+use webhook::Webhook;
 
-pub use compressor::{Algorithm, Statistics};
+pub use compressor::{is_compressible, Algorithm, Compressor, Statistics};
+
+/// Port the API listens on unless the bind address specifies one
+const DEFAULT_PORT: u16 = 8088;
 
 pub struct Options {
+    /// Address the API listens on, `[::]` binds dual-stack on most systems
+    bind: SocketAddr,
+    /// Peers allowed to change deployments, everyone if unset
+    allowed: Option<Vec<Cidr>>,
+    /// Applies `allowed` to read-only requests as well
+    restrict_reads: bool,
     storage: PathBuf,
+    /// Stores bundles in subdirectories to keep large instances manageable
+    storage_sharded: bool,
+    /// Number of replaced versions kept per bundle for rollbacks
+    history_depth: usize,
+    /// Number of bundles unpacked and compressed in parallel on startup
+    load_concurrency: usize,
+    /// Decompresses every generated sidecar to make sure it matches its original
+    verify_compression: bool,
+    /// Compresses API responses for clients which accept gzip
+    compress_responses: bool,
+    /// Whether Caddy includes the rate limit module, without it bundles may not set `rate_limit`
+    rate_limits: bool,
+    /// Caddy handlers bundles may use in `caddy_extra`, which is refused entirely if empty
+    extra_handlers: Vec<String>,
+    /// Percentage of savings below which a bundle is flagged as degraded
+    min_savings: Option<f64>,
+    /// Limits on the unpacked size and entry count of a single bundle
+    unpack_limits: UnpackLimits,
+    /// Time after which incomplete chunked uploads are discarded
+    chunk_expiry: Duration,
     domains: Vec<String>,
 
+    /// Directory Caddy stores its data in, overridable with `LAUNCH_CADDY_DIR`
     caddy_dir: PathBuf,
+    /// Base URL or `unix:` socket path of the Caddy admin API, overridable with `LAUNCH_CADDY_ADMIN`
     caddy_endpoint: String,
+    /// How often the live Caddy config is checked and re-applied if it drifted
+    reconcile_interval: Option<Duration>,
 
+    /// Certificates issued through ACME, enabled with `LAUNCH_TLS` and issued by `LAUNCH_ACME_CA`
     tls: Option<TlsConfig>,
+    log_dir: Option<PathBuf>,
+    /// Whether the server starts in maintenance mode, answering all requests with a 503
+    maintenance: bool,
+    /// HTML page served while in maintenance mode
+    maintenance_page: Option<PathBuf>,
+    /// Notified with the outcome of every upload
+    webhook: Option<Webhook>,
+    /// Directory bundles are unpacked into instead of the system temp directory
+    temp_dir: Option<PathBuf>,
     kube_service: Option<String>,
 }
 
 pub fn run() -> anyhow::Result<()> {
     let options = Options::default();
+
+    // `TempDir` always uses the system temp directory, which is taken from the environment
+    if let Some(dir) = &options.temp_dir {
+        std::fs::create_dir_all(dir)?;
+        std::env::set_var(if cfg!(windows) { "TMP" } else { "TMPDIR" }, dir);
+    }
+
+    if let Some(allowed) = &options.allowed {
+        let allowed = allowed.iter().map(Cidr::to_string).collect::<Vec<_>>();
+        println!("Only accepting changes from {}", allowed.join(", "));
+    }
+
     let mut server = Server::new(options).expect("failed to create server");
 
-    println!("Listening on 0.0.0.0:8088");
-    server.listen(8088);
+    let bind = server.bind();
+    println!("Listening on {bind}");
+    server.listen(bind);
 
     Ok(())
 }
@@ -38,20 +103,110 @@ impl Default for Options {
             .split(",")
             .map(|d| [d.into(), format!("*.{d}")])
             .flatten()
-            .collect();
+            .collect::<Vec<_>>();
+
+        // TLS is opted into explicitly, the ACME credentials are only required once it is
+        let tls = std::env::var_os("LAUNCH_TLS").map(|_| TlsConfig {
+            subjects: domains.clone(),
+            email: std::env::var("LAUNCH_ACME_EMAIL").expect("ACME email not found in env"),
+            token: std::env::var("LAUNCH_CLOUDFLARE_TOKEN")
+                .expect("Cloudflare token not found in env"),
+            ca: std::env::var("LAUNCH_ACME_CA")
+                .map(CaEndpoint::from)
+                .unwrap_or_default(),
+            redirect: std::env::var("LAUNCH_HTTPS_REDIRECT")
+                .map(|v| !matches!(v.as_str(), "0" | "false" | "no"))
+                .unwrap_or(true),
+        });
+
+        let bind = std::env::var("LAUNCH_BIND")
+            .map(|bind| parse_bind(&bind).expect("invalid bind address in LAUNCH_BIND"))
+            .unwrap_or((Ipv4Addr::UNSPECIFIED, DEFAULT_PORT).into());
+
+        let allowed = std::env::var("LAUNCH_ALLOW_CIDRS").ok().map(|cidrs| {
+            cidrs
+                .split(',')
+                .filter(|cidr| !cidr.trim().is_empty())
+                .map(|cidr| cidr.parse().expect("invalid range in LAUNCH_ALLOW_CIDRS"))
+                .collect()
+        });
 
         Options {
+            bind,
+            allowed,
+            restrict_reads: std::env::var_os("LAUNCH_RESTRICT_READS").is_some(),
             kube_service: Some(
                 std::env::var("LAUNCH_SERVICE").expect("Kubernetes service name not found in env"),
             ),
 
             storage: "/var/www/bundles".into(),
+            storage_sharded: std::env::var_os("LAUNCH_STORAGE_SHARDED").is_some(),
+            history_depth: std::env::var("LAUNCH_HISTORY_DEPTH")
+                .ok()
+                .and_then(|d| d.parse().ok())
+                .unwrap_or(0),
+            load_concurrency: std::env::var("LAUNCH_LOAD_CONCURRENCY")
+                .ok()
+                .and_then(|c| c.parse().ok())
+                .or_else(|| std::thread::available_parallelism().ok().map(Into::into))
+                .unwrap_or(1),
+            verify_compression: std::env::var_os("LAUNCH_VERIFY_COMPRESSION").is_some(),
+            compress_responses: std::env::var_os("LAUNCH_UNCOMPRESSED_RESPONSES").is_none(),
+            rate_limits: std::env::var_os("LAUNCH_RATELIMIT_MODULE").is_some(),
+            extra_handlers: std::env::var("LAUNCH_CADDY_EXTRA_HANDLERS")
+                .map(|handlers| {
+                    handlers
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|handler| !handler.is_empty())
+                        .map(Into::into)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            min_savings: std::env::var("LAUNCH_MIN_SAVINGS")
+                .ok()
+                .and_then(|s| s.parse().ok()),
+            unpack_limits: UnpackLimits {
+                max_size: std::env::var("LAUNCH_UNPACK_MAX_SIZE")
+                    .ok()
+                    .and_then(|s| s.parse().ok()),
+                max_entries: std::env::var("LAUNCH_UNPACK_MAX_ENTRIES")
+                    .ok()
+                    .and_then(|s| s.parse().ok()),
+            },
+            chunk_expiry: Duration::from_secs(24 * 60 * 60),
             domains,
 
-            caddy_dir: "/etc/caddy".into(),
-            caddy_endpoint: "http://localhost:2019".into(),
+            caddy_dir: std::env::var_os("LAUNCH_CADDY_DIR")
+                .map(PathBuf::from)
+                .unwrap_or("/etc/caddy".into()),
+            caddy_endpoint: std::env::var("LAUNCH_CADDY_ADMIN")
+                .map(|url| url.trim_end_matches('/').into())
+                .unwrap_or("http://localhost:2019".into()),
+            reconcile_interval: std::env::var("LAUNCH_RECONCILE_INTERVAL")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .map(Duration::from_secs),
 
-            tls: None,
+            tls,
+            log_dir: std::env::var_os("LAUNCH_LOG_DIR").map(PathBuf::from),
+            maintenance: std::env::var_os("LAUNCH_MAINTENANCE").is_some(),
+            maintenance_page: std::env::var_os("LAUNCH_MAINTENANCE_PAGE").map(PathBuf::from),
+            webhook: std::env::var("LAUNCH_WEBHOOK").ok().map(Webhook),
+            temp_dir: std::env::var_os("LAUNCH_TMP").map(PathBuf::from),
         }
     }
 }
+
+/// Parses a socket address like `[::]:8080` or `0.0.0.0`, an address alone using the default port
+fn parse_bind(bind: &str) -> Option<SocketAddr> {
+    let bind = bind.trim();
+
+    bind.parse::<SocketAddr>().ok().or_else(|| {
+        bind.trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, DEFAULT_PORT))
+    })
+}
@@ -1,43 +1,570 @@
-use crate::BundleConfig;
+use crate::{BundleConfig, HistoryEntry, CONFIG_ENTRY};
+use flate2::read::GzDecoder;
 use std::{
-    fs::{create_dir_all, read_dir, remove_file, File},
-    io::{self, ErrorKind, Read},
-    path::{Path, PathBuf},
+    fs::{create_dir_all, read_dir, remove_dir_all, remove_file, rename, File, OpenOptions},
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tar::Archive;
+use tar::{Archive, EntryType};
 use ulid::Ulid;
 
-pub struct BundleStorage(PathBuf);
+/// Bytes a tar entry may take beyond its contents, covering its header, padding and long names
+const ENTRY_OVERHEAD: u64 = 4096;
+
+pub struct BundleStorage {
+    root: PathBuf,
+    /// Whether new bundles are stored in subdirectories instead of one flat directory
+    sharded: bool,
+    limits: UnpackLimits,
+}
+
+/// Bounds on what a single bundle may expand to, guarding against decompression bombs
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UnpackLimits {
+    /// Total bytes of all entries combined
+    pub max_size: Option<u64>,
+    /// Number of entries in the archive
+    pub max_entries: Option<usize>,
+}
+
+impl UnpackLimits {
+    /// Size an archive within the limits may have, allowing for the headers of every entry
+    ///
+    /// Without an entry limit, headers may take up as much space as the contents themselves.
+    fn max_archive_size(&self) -> Option<u64> {
+        let size = self.max_size?;
+        let overhead = match self.max_entries {
+            Some(entries) => (entries as u64 + 1).saturating_mul(ENTRY_OVERHEAD),
+            None => size,
+        };
+
+        Some(size.saturating_add(overhead))
+    }
+}
 
 impl BundleStorage {
-    pub fn new(root: PathBuf) -> io::Result<Self> {
+    pub fn new(root: PathBuf, sharded: bool) -> io::Result<Self> {
         create_dir_all(&root)?;
-        Ok(Self(root))
+        Ok(Self {
+            root,
+            sharded,
+            limits: UnpackLimits::default(),
+        })
+    }
+
+    pub fn with_limits(mut self, limits: UnpackLimits) -> Self {
+        self.limits = limits;
+        self
     }
 
+    fn flat_path(&self, id: Ulid) -> PathBuf {
+        self.root.join(format!("{}.launch", id.to_string()))
+    }
+
+    /// Bundles are sharded by the last two characters of their id, as the leading
+    /// characters encode the timestamp and barely change between deployments.
+    fn sharded_path(&self, id: Ulid) -> PathBuf {
+        let id = id.to_string();
+        self.root
+            .join(&id[id.len() - 2..])
+            .join(format!("{id}.launch"))
+    }
+
+    /// Location of an existing bundle, looking at both layouts
     fn bundle_path(&self, id: Ulid) -> PathBuf {
-        self.0.join(format!("{}.launch", id.to_string()))
+        let (preferred, other) = if self.sharded {
+            (self.sharded_path(id), self.flat_path(id))
+        } else {
+            (self.flat_path(id), self.sharded_path(id))
+        };
+
+        if !preferred.exists() && other.exists() {
+            other
+        } else {
+            preferred
+        }
+    }
+
+    /// Directory holding the chunks of an upload which has not been committed yet
+    fn partial_path(&self, id: Ulid) -> PathBuf {
+        self.root.join(format!("{}.partial", id.to_string()))
+    }
+
+    /// Append-only log of deployments to a domain
+    fn history_path(&self, domain: &str) -> io::Result<PathBuf> {
+        if domain.is_empty()
+            || !domain
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '*'))
+            || domain.contains("..")
+        {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "invalid domain"));
+        }
+
+        Ok(self.root.join("history").join(format!("{domain}.jsonl")))
+    }
+
+    /// Holds the unix timestamp after which a bundle is removed automatically
+    fn expiry_path(&self, id: Ulid) -> PathBuf {
+        self.root.join("expiry").join(id.to_string())
+    }
+
+    /// Archives replaced by newer uploads of a bundle, the most recent one at index zero
+    fn retained_path(&self, id: Ulid, index: usize) -> PathBuf {
+        self.root
+            .join("retained")
+            .join(id.to_string())
+            .join(format!("{index}.launch"))
+    }
+
+    /// Archive uploaded to be activated later, kept apart from the one being served
+    fn prepared_path(&self, id: Ulid) -> PathBuf {
+        self.root.join("prepared").join(format!("{id}.launch"))
+    }
+
+    /// Holds the time to live in seconds of a prepared archive, applied once it is activated
+    fn prepared_ttl_path(&self, id: Ulid) -> PathBuf {
+        self.root.join("prepared").join(format!("{id}.ttl"))
+    }
+
+    /// Config embedded in the current archive, cached to avoid scanning the archive for it
+    fn meta_path(&self, id: Ulid) -> PathBuf {
+        let mut path = self.bundle_path(id).into_os_string();
+        path.push(".meta.json");
+        PathBuf::from(path)
+    }
+
+    /// Holds a config changed after the upload, taking precedence over the one in the archive
+    fn config_path(&self, id: Ulid) -> PathBuf {
+        self.root.join("config").join(format!("{id}.json"))
     }
 
     pub fn remove(&self, id: Ulid) -> io::Result<()> {
-        match remove_file(self.bundle_path(id)) {
-            Ok(_) => Ok(()),
-            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        self.forget_metadata(id)?;
+
+        for path in [
+            self.flat_path(id),
+            self.sharded_path(id),
+            self.expiry_path(id),
+            self.config_path(id),
+            self.prepared_path(id),
+            self.prepared_ttl_path(id),
+        ] {
+            match remove_file(path) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        match remove_dir_all(self.root.join("retained").join(id.to_string())) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Path new archives of a bundle are written to in the configured layout
+    fn preferred_path(&self, id: Ulid) -> PathBuf {
+        if self.sharded {
+            self.sharded_path(id)
+        } else {
+            self.flat_path(id)
+        }
+    }
+
+    /// Stores the archive of an upload, retaining up to `depth` earlier ones
+    ///
+    /// The archive is only moved into place once it was received completely, so a failed
+    /// upload leaves the current archive of the bundle where it is.
+    pub fn add(&self, id: Ulid, data: &mut dyn Read, depth: usize) -> io::Result<()> {
+        let incoming = write_incoming(&self.preferred_path(id), data)?;
+
+        self.replace(id, &incoming, depth).inspect_err(|_| {
+            remove_file(&incoming).ok();
+        })
+    }
+
+    /// Decompresses an uploaded archive, failing once it grows beyond what could still unpack
+    pub fn decode_gzip<'a>(&self, data: impl Read + 'a) -> Box<dyn Read + 'a> {
+        let decoded = GzDecoder::new(data);
+
+        match self.limits.max_archive_size() {
+            Some(remaining) => Box::new(Bounded {
+                inner: decoded,
+                remaining,
+            }),
+            None => Box::new(decoded),
+        }
+    }
+
+    /// Stores an archive without replacing the one currently served, see [`Self::activate`]
+    pub fn add_prepared(&self, id: Ulid, data: &mut dyn Read) -> io::Result<()> {
+        write_archive(&self.prepared_path(id), data)
+    }
+
+    /// Replaces the archive of a bundle with its prepared one, retaining up to `depth` old ones
+    pub fn activate(&self, id: Ulid, depth: usize) -> io::Result<()> {
+        if !self.prepared_path(id).exists() {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                "no prepared version found",
+            ));
+        }
+
+        self.replace(id, &self.prepared_path(id), depth)?;
+
+        match remove_file(self.prepared_ttl_path(id)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remembers the time to live of the prepared archive until it is activated
+    pub fn set_prepared_ttl(&self, id: Ulid, ttl: Option<Duration>) -> io::Result<()> {
+        match ttl {
+            Some(ttl) => std::fs::write(self.prepared_ttl_path(id), ttl.as_secs().to_string()),
+            None => match remove_file(self.prepared_ttl_path(id)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    /// Time to live the prepared archive was uploaded with, if any
+    pub fn prepared_ttl(&self, id: Ulid) -> io::Result<Option<Duration>> {
+        match std::fs::read_to_string(self.prepared_ttl_path(id)) {
+            Ok(ttl) => ttl
+                .trim()
+                .parse()
+                .map(|ttl| Some(Duration::from_secs(ttl)))
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
             Err(e) => Err(e),
         }
     }
 
-    pub fn add(&self, id: Ulid, data: &mut dyn Read) -> io::Result<()> {
-        let mut file = File::create(self.bundle_path(id))?;
-        io::copy(data, &mut file)?;
+    /// Moves `source` in place of the current archive, retaining up to `depth` earlier ones
+    ///
+    /// Older archives are only dropped once the new one is in place. Any failure before that
+    /// moves the served archive back and leaves `source` where it was.
+    fn replace(&self, id: Ulid, source: &Path, depth: usize) -> io::Result<()> {
+        let path = self.preferred_path(id);
+        let retained = depth > 0 && self.bundle_path(id).exists();
+
+        if retained {
+            self.retain(id)?;
+        }
+
+        let placed = self
+            .forget_metadata(id)
+            .and_then(|_| rename(source, &path))
+            .map_err(|e| (e, false))
+            .and_then(|_| self.cache_metadata(id).map_err(|e| (e, true)));
+
+        if let Err((e, moved)) = placed {
+            if moved {
+                rename(&path, source).ok();
+            }
+
+            self.forget_metadata(id).ok();
+
+            if retained {
+                self.unretain(id).ok();
+            }
+
+            return Err(e);
+        }
+
+        self.trim(id, depth)?;
+
+        // A new archive brings its own config, replacing any earlier changes
+        match remove_file(self.config_path(id)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Moves the current archive of a bundle aside, in front of those retained earlier
+    ///
+    /// Nothing is dropped here, see [`Self::trim`], so [`Self::unretain`] can undo it.
+    fn retain(&self, id: Ulid) -> io::Result<()> {
+        let mut count = 0;
+        while self.retained_path(id, count).exists() {
+            count += 1;
+        }
+
+        for index in (0..count).rev() {
+            rename(
+                self.retained_path(id, index),
+                self.retained_path(id, index + 1),
+            )?;
+        }
+
+        self.forget_metadata(id)?;
+        create_dir_all(self.root.join("retained").join(id.to_string()))?;
+        rename(self.bundle_path(id), self.retained_path(id, 0))
+    }
+
+    /// Removes all but the `depth` most recently retained archives of a bundle
+    pub fn trim(&self, id: Ulid, depth: usize) -> io::Result<()> {
+        let mut index = depth;
+        while self.retained_path(id, index).exists() {
+            remove_file(self.retained_path(id, index))?;
+            index += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the archive of a bundle with the most recently retained one
+    pub fn restore(&self, id: Ulid) -> io::Result<()> {
+        self.unretain(id)?;
+
+        match remove_file(self.config_path(id)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Moves the most recently retained archive back in place, keeping config changes
+    fn unretain(&self, id: Ulid) -> io::Result<()> {
+        self.forget_metadata(id)?;
+        rename(self.retained_path(id, 0), self.bundle_path(id)).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => {
+                io::Error::new(ErrorKind::NotFound, "no previous version retained")
+            }
+            _ => e,
+        })?;
+
+        let mut index = 1;
+        while self.retained_path(id, index).exists() {
+            rename(
+                self.retained_path(id, index),
+                self.retained_path(id, index - 1),
+            )?;
+            index += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Stores one chunk of a partial upload, replacing any previous attempt at the same index
+    pub fn add_chunk(&self, id: Ulid, index: usize, data: &mut dyn Read) -> io::Result<()> {
+        // Written atomically, so an interrupted chunk is never committed as a complete one
+        write_archive(&self.partial_path(id).join(format!("{index:08}")), data)
+    }
+
+    /// Assembles the first `count` chunks of a partial upload into a regular bundle
+    ///
+    /// With `gzip` set, the chunks form a gzip stream of the archive which is decoded first.
+    /// With `prepared` set, the archive is stored as the prepared version of the bundle.
+    pub fn commit_chunks(
+        &self,
+        id: Ulid,
+        count: usize,
+        gzip: bool,
+        prepared: bool,
+        depth: usize,
+    ) -> io::Result<()> {
+        let dir = self.partial_path(id);
+        let mut data: Box<dyn Read> = Box::new(io::empty());
+
+        for index in 0..count {
+            let chunk = File::open(dir.join(format!("{index:08}"))).map_err(|e| {
+                if e.kind() == ErrorKind::NotFound {
+                    io::Error::new(ErrorKind::NotFound, format!("chunk {index} is missing"))
+                } else {
+                    e
+                }
+            })?;
+
+            data = Box::new(data.chain(chunk));
+        }
+
+        if gzip {
+            data = self.decode_gzip(data);
+        }
+
+        if prepared {
+            self.add_prepared(id, &mut data)?;
+        } else {
+            self.add(id, &mut data, depth)?;
+        }
+
+        remove_dir_all(dir)?;
+
+        Ok(())
+    }
+
+    /// Removes partial uploads which have not received any chunks for longer than `max_age`
+    pub fn expire_chunks(&self, max_age: Duration) -> io::Result<()> {
+        for entry in read_dir(&self.root)? {
+            let entry = entry?;
+
+            if entry.file_type()?.is_dir()
+                && entry
+                    .path()
+                    .extension()
+                    .map(|e| e.eq_ignore_ascii_case("partial"))
+                    .unwrap_or_default()
+                && entry.metadata()?.modified()?.elapsed().unwrap_or_default() > max_age
+            {
+                remove_dir_all(entry.path())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Schedules a bundle for removal once the given time has passed
+    pub fn set_expiry(&self, id: Ulid, expiry: SystemTime) -> io::Result<()> {
+        let timestamp = expiry
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        create_dir_all(self.root.join("expiry"))?;
+        std::fs::write(self.expiry_path(id), timestamp.to_string())
+    }
+
+    /// Keeps a bundle around until it is removed explicitly
+    pub fn clear_expiry(&self, id: Ulid) -> io::Result<()> {
+        match remove_file(self.expiry_path(id)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Persists a changed config of a bundle without touching its archive
+    pub fn set_metadata(&self, id: Ulid, config: &BundleConfig) -> io::Result<()> {
+        create_dir_all(self.root.join("config"))?;
+        std::fs::write(self.config_path(id), serde_json::to_vec(config)?)
+    }
+
+    /// Lists all bundles whose expiry lies before `now`
+    pub fn expired(&self, now: SystemTime) -> io::Result<Vec<Ulid>> {
+        let dir = match read_dir(self.root.join("expiry")) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        let mut expired = Vec::new();
+
+        for entry in dir {
+            let entry = entry?;
+
+            let id = match entry.file_name().to_str().map(Ulid::from_string) {
+                Some(Ok(id)) => id,
+                _ => continue,
+            };
+
+            // A single unreadable file must not keep every other bundle from expiring
+            let timestamp = match std::fs::read_to_string(entry.path()).and_then(|timestamp| {
+                timestamp
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+            }) {
+                Ok(timestamp) => timestamp,
+                Err(e) => {
+                    eprintln!("ignoring malformed expiry of bundle {id}: {e}");
+                    continue;
+                }
+            };
+
+            if timestamp <= now {
+                expired.push(id);
+            }
+        }
+
+        Ok(expired)
+    }
+
+    pub fn append_history(&self, domain: &str, entry: &HistoryEntry) -> io::Result<()> {
+        let path = self.history_path(domain)?;
+        create_dir_all(self.root.join("history"))?;
+
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
         file.sync_all()?;
+
         Ok(())
     }
 
+    pub fn history(&self, domain: &str) -> io::Result<Vec<HistoryEntry>> {
+        let file = match File::open(self.history_path(domain)?) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut entries = Vec::new();
+
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+
+            if !line.trim().is_empty() {
+                entries.push(serde_json::from_str(&line)?);
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Lists all stored bundles, regardless of whether they use the flat or sharded layout
     pub fn enumerate(&self) -> io::Result<Vec<Ulid>> {
         let mut bundles = Vec::new();
+        self.enumerate_dir(&self.root, &mut bundles)?;
 
-        for entry in read_dir(&self.0)? {
+        for entry in read_dir(&self.root)? {
+            let entry = entry?;
+
+            if entry.file_type()?.is_dir() && entry.file_name().len() == 2 {
+                self.enumerate_dir(&entry.path(), &mut bundles)?;
+            }
+        }
+
+        bundles.sort();
+        bundles.dedup();
+
+        Ok(bundles)
+    }
+
+    /// Lists all prepared archives along with the unix timestamp they were uploaded at
+    pub fn enumerate_prepared(&self) -> io::Result<Vec<(Ulid, u64)>> {
+        let mut bundles = Vec::new();
+
+        match self.enumerate_dir(&self.root.join("prepared"), &mut bundles) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+
+        bundles.sort();
+        bundles
+            .into_iter()
+            .map(|id| {
+                let modified = self.prepared_path(id).metadata()?.modified()?;
+                let timestamp = modified
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+
+                Ok((id, timestamp))
+            })
+            .collect()
+    }
+
+    fn enumerate_dir(&self, dir: &Path, bundles: &mut Vec<Ulid>) -> io::Result<()> {
+        for entry in read_dir(dir)? {
             let entry = entry?;
 
             if entry.file_type()?.is_file()
@@ -61,33 +588,407 @@ impl BundleStorage {
             }
         }
 
-        Ok(bundles)
+        Ok(())
+    }
+
+    /// Opens the archive of a stored bundle as it was uploaded
+    pub fn open(&self, id: Ulid) -> io::Result<File> {
+        File::open(self.bundle_path(id))
     }
 
     pub fn metadata(&self, id: Ulid) -> io::Result<BundleConfig> {
-        let file = File::open(&self.bundle_path(id))?;
-        let mut archive = Archive::new(file);
+        match File::open(self.config_path(id)) {
+            Ok(file) => return Ok(serde_json::from_reader(file)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
 
-        for entry in archive.entries()? {
-            let mut entry = entry?;
+        match File::open(self.meta_path(id)) {
+            Ok(file) => match serde_json::from_reader(file) {
+                Ok(config) => return Ok(config),
+                Err(e) => eprintln!("ignoring unreadable metadata cache of {id}: {e}"),
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
 
-            if entry.path()?.ends_with("launch.config") {
-                let options: BundleConfig = serde_json::from_reader(&mut entry)?;
-                return Ok(options);
-            }
+        let config = read_config(&self.bundle_path(id))?;
+
+        if let Err(e) = std::fs::write(self.meta_path(id), serde_json::to_vec(&config)?) {
+            eprintln!("failed to cache metadata of {id}: {e}");
+        }
+
+        Ok(config)
+    }
+
+    /// Caches the config embedded in the current archive, if it has one
+    fn cache_metadata(&self, id: Ulid) -> io::Result<()> {
+        match read_config(&self.bundle_path(id)) {
+            Ok(config) => std::fs::write(self.meta_path(id), serde_json::to_vec(&config)?),
+            // Surfaces once the bundle is loaded, failing the upload here would hide the cause
+            Err(_) => Ok(()),
         }
+    }
 
-        Err(io::Error::new(
-            ErrorKind::NotFound,
-            "no launch config found",
-        ))
+    /// Drops the cached config of the current archive before it is moved or replaced
+    fn forget_metadata(&self, id: Ulid) -> io::Result<()> {
+        match remove_file(self.meta_path(id)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
     }
 
-    pub fn unpack(&self, id: Ulid, destination: impl AsRef<Path>) -> io::Result<()> {
-        let mut archive = Archive::new(File::open(&self.bundle_path(id))?);
-        create_dir_all(&destination)?;
+    /// Config of the prepared archive of a bundle
+    pub fn prepared_metadata(&self, id: Ulid) -> io::Result<BundleConfig> {
+        read_config(&self.prepared_path(id))
+    }
+
+    /// Unpacks a bundle entry by entry, reporting the relative path of each regular file written
+    pub fn unpack_with(
+        &self,
+        id: Ulid,
+        destination: &Path,
+        on_file: impl FnMut(PathBuf),
+    ) -> io::Result<()> {
+        self.unpack_archive(&self.bundle_path(id), destination, on_file)
+    }
+
+    /// Unpacks the prepared archive of a bundle like [`Self::unpack_with`]
+    pub fn unpack_prepared_with(
+        &self,
+        id: Ulid,
+        destination: &Path,
+        on_file: impl FnMut(PathBuf),
+    ) -> io::Result<()> {
+        self.unpack_archive(&self.prepared_path(id), destination, on_file)
+    }
+
+    fn unpack_archive(
+        &self,
+        path: &Path,
+        destination: &Path,
+        mut on_file: impl FnMut(PathBuf),
+    ) -> io::Result<()> {
+        let mut archive = Archive::new(File::open(path)?);
+        create_dir_all(destination)?;
         archive.set_overwrite(true);
-        archive.unpack(&destination)?;
+        // Modification times feed into the ETag and Last-Modified headers emitted by Caddy
+        archive.set_preserve_mtime(true);
+
+        let mut total_size = 0;
+        let mut symlinks = Vec::new();
+
+        for (index, entry) in archive.entries()?.enumerate() {
+            let mut entry = entry?;
+            let is_file = entry.header().entry_type().is_file();
+            let is_dir = entry.header().entry_type().is_dir();
+
+            total_size += entry.size();
+
+            if let Some(max) = self.limits.max_entries.filter(|max| index >= *max) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("bundle has more than {max} entries"),
+                ));
+            }
+
+            if let Some(max) = self.limits.max_size.filter(|max| total_size > *max) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("bundle expands to more than {max} bytes"),
+                ));
+            }
+
+            let relative = entry
+                .path()?
+                .components()
+                .filter(|c| matches!(c, Component::Normal(_)))
+                .collect::<PathBuf>();
+
+            match entry.header().entry_type() {
+                EntryType::Regular | EntryType::Continuous | EntryType::Directory => {}
+                EntryType::XGlobalHeader => continue,
+                EntryType::Symlink => {
+                    let target = entry.link_name()?.unwrap_or_default().into_owned();
+                    symlinks.push((relative.clone(), target));
+                }
+                other => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("unsupported entry {} of type {other:?}", relative.display()),
+                    ))
+                }
+            }
+
+            if !entry.unpack_in(destination)? {
+                continue;
+            }
+
+            if is_file || is_dir {
+                normalize_permissions(&destination.join(&relative), is_dir)?;
+            }
+
+            if is_file {
+                on_file(relative);
+            }
+        }
+
+        // Checked once everything is unpacked, as later entries may replace parts of the path
+        for (link, target) in symlinks {
+            if !stays_within(destination, &link, &target)? {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("symlink {} points outside of the bundle", link.display()),
+                ));
+            }
+        }
+
         Ok(())
     }
 }
+
+/// Fails reads beyond a number of bytes, where [`Read::take`] would end the data silently
+struct Bounded<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> Read for Bounded<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+
+        self.remaining = self.remaining.checked_sub(read as u64).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                "decompressed archive exceeds the unpack limits",
+            )
+        })?;
+
+        Ok(read)
+    }
+}
+
+/// Writes an archive atomically, leaving any existing one at `path` alone if it fails
+fn write_archive(path: &Path, data: &mut dyn Read) -> io::Result<()> {
+    let incoming = write_incoming(path, data)?;
+    rename(incoming, path)
+}
+
+/// Writes an archive next to `path` and returns where, removing it again if `data` fails
+fn write_incoming(path: &Path, data: &mut dyn Read) -> io::Result<PathBuf> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+
+    let mut incoming = path.as_os_str().to_owned();
+    incoming.push(".incoming");
+    let incoming = PathBuf::from(incoming);
+
+    let result = File::create(&incoming).and_then(|mut file| {
+        io::copy(data, &mut file)?;
+        file.sync_all()
+    });
+
+    match result {
+        Ok(()) => Ok(incoming),
+        Err(e) => {
+            remove_file(&incoming).ok();
+            Err(e)
+        }
+    }
+}
+
+/// Reads the config embedded in an archive, which clients place as its first entry
+fn read_config(path: &Path) -> io::Result<BundleConfig> {
+    let mut archive = Archive::new(File::open(path)?);
+    let corrupt = |reason: String| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("archive {} is corrupt: {reason}", path.display()),
+        )
+    };
+
+    let mut entry = match archive.entries()?.next() {
+        Some(Ok(entry)) => entry,
+        Some(Err(e)) => return Err(corrupt(format!("unreadable first entry ({e})"))),
+        None => return Err(corrupt("it is empty or truncated".into())),
+    };
+
+    let name = entry.path()?.into_owned();
+
+    if !name.ends_with(CONFIG_ENTRY) {
+        return Err(corrupt(format!(
+            "expected {CONFIG_ENTRY} as the first entry, found {}",
+            name.display()
+        )));
+    }
+
+    serde_json::from_reader(&mut entry)
+        .map_err(|e| corrupt(format!("unreadable {CONFIG_ENTRY} ({e})")))
+}
+
+/// Whether a symlink at `link` with the given target resolves to a path within `root`
+///
+/// Targets must not pass through other symlinks, as those could point anywhere without the path
+/// leaving the bundle on paper. Climbing up with `..` is fine otherwise, as every directory it
+/// leaves is a real one within `root`.
+fn stays_within(root: &Path, link: &Path, target: &Path) -> io::Result<bool> {
+    let parent = link.parent().unwrap_or(Path::new(""));
+    let mut path = root.to_path_buf();
+
+    for component in parent.components().chain(target.components()) {
+        // Ending on another symlink is fine, as that one is checked on its own
+        match path.symlink_metadata() {
+            Ok(metadata) if metadata.file_type().is_symlink() => return Ok(false),
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::ParentDir if path != root => {
+                path.pop();
+            }
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return Ok(false),
+        }
+    }
+
+    Ok(true)
+}
+
+/// Replaces the mode an archive entry carried with one fit for static content
+///
+/// Served files only need to be readable, so executable or otherwise unusual modes set by
+/// the client are dropped in favour of `0644` for files and `0755` for directories.
+#[cfg(unix)]
+fn normalize_permissions(path: &Path, is_dir: bool) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = if is_dir { 0o755 } else { 0o644 };
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn normalize_permissions(_path: &Path, _is_dir: bool) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tar::{Builder, Header};
+    use temp_dir::TempDir;
+
+    /// Entries of a test archive, by path
+    enum Entry<'a> {
+        File(&'a str, &'a [u8]),
+        /// File or directory carrying the given mode, which unpacking has to normalise
+        Mode(&'a str, u32, EntryType),
+        Symlink(&'a str, &'a str),
+        Fifo(&'a str),
+    }
+
+    /// Unpacks an archive built from `entries` into a fresh directory
+    fn unpack(entries: &[Entry]) -> (TempDir, io::Result<()>) {
+        let dir = TempDir::new().unwrap();
+        let mut builder = Builder::new(Vec::new());
+
+        for entry in entries {
+            let mut header = Header::new_gnu();
+            header.set_mode(0o644);
+
+            match entry {
+                Entry::File(path, data) => {
+                    header.set_size(data.len() as u64);
+                    builder.append_data(&mut header, path, *data).unwrap();
+                }
+                Entry::Mode(path, mode, kind) => {
+                    header.set_entry_type(*kind);
+                    header.set_mode(*mode);
+                    header.set_size(0);
+                    builder.append_data(&mut header, path, io::empty()).unwrap();
+                }
+                Entry::Symlink(path, target) => {
+                    header.set_entry_type(EntryType::Symlink);
+                    header.set_size(0);
+                    builder.append_link(&mut header, path, target).unwrap();
+                }
+                Entry::Fifo(path) => {
+                    header.set_entry_type(EntryType::Fifo);
+                    header.set_size(0);
+                    builder.append_data(&mut header, path, io::empty()).unwrap();
+                }
+            }
+        }
+
+        let archive = dir.path().join("bundle.launch");
+        std::fs::write(&archive, builder.into_inner().unwrap()).unwrap();
+
+        let storage = BundleStorage::new(dir.path().join("storage"), false).unwrap();
+        let result = storage.unpack_archive(&archive, &dir.path().join("out"), |_| {});
+
+        (dir, result)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn normalises_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (dir, result) = unpack(&[
+            Entry::Mode("private", 0o700, EntryType::Directory),
+            Entry::Mode("private/run.sh", 0o4777, EntryType::Regular),
+            Entry::Mode("hidden.html", 0o200, EntryType::Regular),
+        ]);
+        result.unwrap();
+
+        let mode = |path: &str| {
+            let metadata = std::fs::metadata(dir.path().join("out").join(path)).unwrap();
+            metadata.permissions().mode() & 0o7777
+        };
+
+        assert_eq!(mode("private"), 0o755);
+        assert_eq!(mode("private/run.sh"), 0o644);
+        assert_eq!(mode("hidden.html"), 0o644);
+    }
+
+    #[test]
+    fn refuses_fifos() {
+        let (dir, result) = unpack(&[Entry::File("index.html", b"hi"), Entry::Fifo("pipe")]);
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(!dir.path().join("out/pipe").exists());
+    }
+
+    #[test]
+    fn accepts_symlinks_within_the_bundle() {
+        let (_dir, result) = unpack(&[
+            Entry::File("assets/app.js", b"app"),
+            Entry::Symlink("latest.js", "assets/app.js"),
+            Entry::Symlink("assets/current.js", "./app.js"),
+            Entry::Symlink("assets/index.html", "../latest.js"),
+        ]);
+
+        result.unwrap();
+    }
+
+    #[test]
+    fn refuses_symlinks_leaving_the_bundle() {
+        for target in ["../secret", "/etc/passwd", "assets/../../secret"] {
+            let (_dir, result) = unpack(&[Entry::Symlink("link", target)]);
+            assert!(result.is_err(), "{target} was accepted");
+        }
+    }
+
+    #[test]
+    fn refuses_symlinks_through_other_symlinks() {
+        // Resolving through `outside` stays within here, but the rule must not depend on that
+        let (_dir, result) = unpack(&[
+            Entry::Symlink("outside", "."),
+            Entry::Symlink("link", "outside/index.html"),
+        ]);
+
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,37 @@
+use super::Statistics;
+use serde::Serialize;
+use std::{thread, time::Duration};
+use ulid::Ulid;
+
+/// Time after which a webhook delivery is given up on
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Endpoint notified about the outcome of every upload
+#[derive(Debug, Clone)]
+pub struct Webhook(pub String);
+
+/// Payload posted to the webhook, carrying either the statistics or the error of a deploy
+#[derive(Debug, Serialize)]
+pub struct DeployEvent {
+    pub id: Ulid,
+    pub domains: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<Statistics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Webhook {
+    /// Delivers the event on a separate thread, so a slow receiver does not delay the response
+    pub fn notify(&self, event: DeployEvent) {
+        let url = self.0.clone();
+
+        thread::spawn(move || {
+            let result = ureq::post(&url).timeout(TIMEOUT).send_json(&event);
+
+            if let Err(e) = result {
+                eprintln!("failed to notify webhook about {}: {e}", event.id);
+            }
+        });
+    }
+}
@@ -1,5 +1,8 @@
-use crate::server::Statistics;
+use crate::server::{Algorithm, Statistics};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use ulid::Ulid;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BundleConfig {
@@ -9,22 +12,393 @@ pub struct BundleConfig {
     /// Where the page will be available
     pub domain: String,
 
+    /// Path prefix the bundle is mounted at, allowing several bundles to share one domain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+
     /// File extensions which should be precompressed
     #[serde(default)]
     pub compress: Vec<String>,
 
     /// Fallback path for serving single-page applications
     pub fallback: Option<String>,
+
+    /// File served for requests to a directory, defaults to `index.html`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<String>,
+
+    /// Candidates tried in order before the fallback, `{path}` being the requested path.
+    /// Defaults to `["{path}", "{path}/{index}"]`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub try_files: Option<Vec<String>>,
+
+    /// Page served with a 404 status for unmatched requests, exclusive with `fallback`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_found: Option<String>,
+
+    /// Renders a listing of directories which lack an index file, exclusive with `fallback`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub browse: bool,
+
+    /// Whether the bundle is served over TLS when the server has it configured
+    #[serde(default = "default_tls")]
+    pub tls: bool,
+
+    /// Tuning of the brotli encoder used for precompression
+    #[serde(default)]
+    pub brotli: BrotliOptions,
+
+    /// Trade-off between compression speed and ratio, e.g. `fast` for preview deployments
+    #[serde(default, skip_serializing_if = "CompressionProfile::is_default")]
+    pub profile: CompressionProfile,
+
+    /// Truncates originals which have a smaller sidecar for every algorithm, saving disk space.
+    /// Clients accepting none of the algorithms are answered with a 406 for those files.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub strip_originals: bool,
+
+    /// Algorithms to precompress with, restricting those of the server. Defaults to all of them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub algorithms: Option<Vec<Algorithm>>,
+
+    /// Limits the requests of each client IP, only accepted by servers which enable
+    /// `LAUNCH_RATELIMIT_MODULE` for a Caddy built with `github.com/mholt/caddy-ratelimit`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimit>,
+
+    /// Redirects answered before any file is looked up, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub redirects: Vec<Redirect>,
+
+    /// Content types by file extension, replacing the ones Caddy would detect, e.g.
+    /// `{"webmanifest": "application/manifest+json"}`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub mime_types: HashMap<String, String>,
+
+    /// Free-form labels for organising deployments, e.g. `team` or `env`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub labels: HashMap<String, String>,
+
+    /// Raw Caddy routes inserted before the file server, e.g. to proxy an API. Servers only
+    /// accept the handlers listed in their `LAUNCH_CADDY_EXTRA_HANDLERS`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caddy_extra: Option<Value>,
+}
+
+impl BundleConfig {
+    /// Normalised path prefix with a leading and without a trailing slash, `None` for the root
+    pub fn path_prefix(&self) -> Option<String> {
+        let path = self.path.as_deref()?.trim_matches('/');
+        (!path.is_empty()).then(|| format!("/{path}"))
+    }
+
+    /// Public URL of the bundle
+    pub fn url(&self) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        format!(
+            "{scheme}://{}{}",
+            self.domain,
+            self.path_prefix().unwrap_or_default()
+        )
+    }
+
+    /// Name of the directory index file
+    pub fn index(&self) -> &str {
+        self.index.as_deref().unwrap_or("index.html")
+    }
+
+    /// Candidates tried in order before the fallback, defaulting to the path and its index
+    pub fn try_files(&self) -> Vec<String> {
+        match &self.try_files {
+            Some(candidates) => candidates.clone(),
+            None => vec!["{path}".into(), format!("{{path}}/{}", self.index())],
+        }
+    }
+
+    /// Checks constraints between fields which serde can not express
+    pub fn validate(&self) -> Result<(), String> {
+        if self.fallback.is_some() && self.not_found.is_some() {
+            return Err("fallback and not_found are mutually exclusive".into());
+        }
+
+        if self.browse && self.fallback.is_some() {
+            return Err("browse and fallback are mutually exclusive".into());
+        }
+
+        if let Some(index) = &self.index {
+            if index.is_empty() || index.contains('/') || matches!(index.as_str(), "." | "..") {
+                return Err(format!(
+                    "invalid index file '{index}', expected a file name"
+                ));
+            }
+        }
+
+        if let Some(prefix) = self.path_prefix() {
+            if prefix
+                .split('/')
+                .any(|segment| matches!(segment, "." | ".."))
+                || prefix.contains(|c: char| c.is_whitespace() || matches!(c, '*' | '?' | '#'))
+            {
+                return Err(format!("invalid path prefix '{prefix}'"));
+            }
+        }
+
+        for redirect in self.redirects.iter() {
+            if !redirect.from.starts_with('/') {
+                return Err(format!(
+                    "redirect source '{}' must start with a slash",
+                    redirect.from
+                ));
+            }
+
+            if !(300..400).contains(&redirect.status) {
+                return Err(format!(
+                    "redirect from '{}' has status {}, expected a 3xx status",
+                    redirect.from, redirect.status
+                ));
+            }
+        }
+
+        if let Some(limit) = &self.rate_limit {
+            if limit.requests == 0 || limit.window == 0 {
+                return Err("rate_limit needs a non-zero number of requests and window".into());
+            }
+        }
+
+        for (extension, mime_type) in self.mime_types.iter() {
+            if extension.is_empty()
+                || !extension
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+            {
+                return Err(format!("invalid extension '{extension}' in mime_types"));
+            }
+
+            if !mime_type.contains('/') || mime_type.contains(|c: char| c.is_control()) {
+                return Err(format!(
+                    "invalid content type '{mime_type}' for extension '{extension}'"
+                ));
+            }
+        }
+
+        match &self.caddy_extra {
+            Some(Value::Array(routes)) if routes.iter().all(Value::is_object) => Ok(()),
+            Some(_) => Err("caddy_extra must be an array of route objects".into()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Number of requests a single client IP may make within a sliding window
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub requests: u32,
+    /// Length of the window in seconds
+    pub window: u64,
+}
+
+/// Answers requests for a path with a redirect to another location
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    /// Path relative to the bundle, ending in `/*` to match everything below it
+    pub from: String,
+    /// Location to redirect to, `:splat` is replaced by the part matched by `*`
+    pub to: String,
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+}
+
+impl Redirect {
+    /// Prefix matched by the source if it ends in `*`, `None` for exact matches
+    pub fn prefix(&self) -> Option<&str> {
+        self.from.strip_suffix('*')
+    }
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
+fn default_tls() -> bool {
+    true
+}
+
+/// Servers which do not report it only keep bundles they are serving
+fn default_served() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct BrotliOptions {
+    /// Base two logarithm of the sliding window size, clamped to 10–24
+    pub window: Option<i32>,
+
+    /// Kind of content the encoder should optimise for
+    #[serde(default)]
+    pub mode: BrotliMode,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BrotliMode {
+    #[default]
+    Generic,
+    Text,
+    Font,
+}
+
+/// Named compression levels, so bundles need not know the level range of each algorithm
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionProfile {
+    /// Lowest levels, for deployments where upload latency matters more than size
+    Fast,
+    /// Levels close to the defaults of the command line tools
+    Balanced,
+    /// Highest levels, taking the most CPU time for the smallest files
+    #[default]
+    Best,
+}
+
+impl CompressionProfile {
+    /// Quality level passed to the encoder of an algorithm
+    pub fn level(self, algorithm: Algorithm) -> u32 {
+        use CompressionProfile::*;
+
+        match (self, algorithm) {
+            (Fast, Algorithm::Gzip) => 1,
+            (Fast, Algorithm::Brotli) => 2,
+            (Balanced, Algorithm::Gzip) => 6,
+            (Balanced, Algorithm::Brotli) => 6,
+            (Best, _) => *algorithm.levels().end(),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        use CompressionProfile::*;
+
+        match self {
+            Fast => "fast",
+            Balanced => "balanced",
+            Best => "best",
+        }
+    }
+
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Bundle {
+    /// Unpacked and compressed, but not served until it is activated. Listed before `Active`
+    /// as untagged deserialization picks the first variant whose fields are all present.
+    Prepared {
+        config: Box<BundleConfig>,
+        stats: Statistics,
+        /// Unix timestamp at which the bundle was prepared
+        prepared: u64,
+    },
     Active {
-        config: BundleConfig,
+        config: Box<BundleConfig>,
         stats: Statistics,
+        /// Served normally, but something about the bundle looks off
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        warning: Option<String>,
+        /// Whether the bundle is part of the config Caddy last accepted, never during maintenance
+        #[serde(default = "default_served")]
+        served: bool,
     },
     Failed {
         error: String,
     },
 }
+
+impl Bundle {
+    /// Name of the variant, as used to filter listings by state
+    pub fn state(&self) -> &'static str {
+        match self {
+            Bundle::Prepared { .. } => "prepared",
+            Bundle::Active { .. } => "active",
+            Bundle::Failed { .. } => "failed",
+        }
+    }
+
+    pub fn config(&self) -> Option<&BundleConfig> {
+        match self {
+            Bundle::Prepared { config, .. } | Bundle::Active { config, .. } => Some(config),
+            Bundle::Failed { .. } => None,
+        }
+    }
+}
+
+/// Line of a bundle list streamed as newline delimited JSON
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListEntry {
+    pub id: Ulid,
+    pub bundle: Bundle,
+}
+
+/// Result of a successful deployment as reported by the server
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Deployment {
+    /// Public URLs under which the bundle is served
+    pub urls: Vec<String>,
+    pub stats: Statistics,
+    /// Deployments which were removed to take over their domain
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replaced: Vec<Ulid>,
+    /// Caddy route generated for the bundle, only reported when asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route: Option<Value>,
+}
+
+/// Reported with a 409 status when the domain of a bundle is already served by another one
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DomainConflict {
+    /// Domain the rejected bundle asked for
+    pub domain: String,
+    /// Domain of the bundle which is already deployed, differs if a wildcard overlaps
+    pub existing: String,
+    /// Deployment currently serving `existing`
+    pub id: Ulid,
+}
+
+impl std::fmt::Display for DomainConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.domain == self.existing {
+            write!(f, "domain already in use by bundle {}", self.id)
+        } else {
+            write!(
+                f,
+                "domain {} overlaps with {} which is already in use by bundle {}",
+                self.domain, self.existing, self.id
+            )
+        }
+    }
+}
+
+impl std::error::Error for DomainConflict {}
+
+/// Reported with a 409 status when an upload would move a deployment to another domain
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DomainChange {
+    /// Deployment the upload is for
+    pub id: Ulid,
+    /// Domain the deployment currently serves
+    pub served: String,
+    /// Domain of the upload
+    pub uploaded: String,
+}
+
+impl std::fmt::Display for DomainChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "deployment {} serves {} but the upload is for {}, \
+             the id may have been copied from another project",
+            self.id, self.served, self.uploaded
+        )
+    }
+}
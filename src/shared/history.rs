@@ -0,0 +1,12 @@
+use crate::server::Statistics;
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+/// Record of a single successful deployment to a domain
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HistoryEntry {
+    /// Seconds since the unix epoch at which the deployment went live
+    pub timestamp: u64,
+    pub id: Ulid,
+    pub stats: Statistics,
+}
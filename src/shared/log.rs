@@ -0,0 +1,11 @@
+use serde::{Deserialize, Serialize};
+
+/// Event the server recorded about a deployment
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LogEntry {
+    /// Position of the entry among all entries of the deployment, used to fetch only newer ones
+    pub sequence: u64,
+    /// Seconds since the unix epoch at which the event happened
+    pub timestamp: u64,
+    pub message: String,
+}
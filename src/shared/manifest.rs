@@ -0,0 +1,26 @@
+use crate::server::Algorithm;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// File served as part of a deployed bundle
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ManifestEntry {
+    /// Path relative to the bundle root, using forward slashes
+    pub path: String,
+    /// Size of the original, even if it was emptied in favour of its sidecars
+    pub size: u64,
+    /// Precompressed sidecars available for this file
+    #[serde(default)]
+    pub encodings: Vec<Algorithm>,
+    /// Size of each sidecar, missing from servers which only report the encodings
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub compressed: HashMap<Algorithm, u64>,
+}
+
+impl ManifestEntry {
+    /// Fraction of the original size saved by the smallest sidecar
+    pub fn savings(&self) -> Option<f64> {
+        let smallest = self.compressed.values().min()?;
+        (self.size > 0).then(|| 1.0 - *smallest as f64 / self.size as f64)
+    }
+}
@@ -1,3 +1,41 @@
 mod bundle;
+mod history;
+mod log;
+mod manifest;
+mod version;
 
-pub use bundle::{Bundle, BundleConfig};
+pub use bundle::{
+    BrotliMode, BrotliOptions, Bundle, BundleConfig, CompressionProfile, Deployment, DomainChange,
+    DomainConflict, ListEntry, RateLimit, Redirect,
+};
+pub use history::HistoryEntry;
+pub use log::LogEntry;
+pub use manifest::ManifestEntry;
+pub use version::VersionInfo;
+
+/// Header carrying the time to live of an upload in seconds
+pub const TTL_HEADER: &str = "X-Launch-TTL";
+
+/// Header identifying retries of the same upload
+pub const IDEMPOTENCY_HEADER: &str = "Idempotency-Key";
+
+/// Header asking the server to replace bundles holding the domain of an upload
+pub const FORCE_HEADER: &str = "X-Launch-Force";
+
+/// Header allowing an upload to move an existing deployment to another domain
+pub const OVERWRITE_HEADER: &str = "X-Launch-Overwrite";
+
+/// Header asking the server to include the generated Caddy route in the deployment response
+pub const VERBOSE_HEADER: &str = "X-Launch-Verbose";
+
+/// Header asking the server to prepare an upload without serving it until it is activated
+pub const PREPARE_HEADER: &str = "X-Launch-Prepare";
+
+/// Header naming the encoding of an archive assembled from chunks, as the commit body is JSON
+pub const ARCHIVE_ENCODING_HEADER: &str = "X-Launch-Archive-Encoding";
+
+/// Name of the bundle config within an archive, which is always its first entry
+pub const CONFIG_ENTRY: &str = "launch.config";
+
+/// Media type of bundle lists streamed as one JSON object per line
+pub const NDJSON: &str = "application/x-ndjson";
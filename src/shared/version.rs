@@ -0,0 +1,28 @@
+use crate::server::Algorithm;
+use serde::{Deserialize, Serialize};
+
+/// Build information reported by a server
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct VersionInfo {
+    /// Crate version the server was built from
+    pub version: String,
+    /// Git commit the server was built from, if it was provided at build time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// Algorithms the server precompresses with
+    pub algorithms: Vec<Algorithm>,
+}
+
+impl VersionInfo {
+    /// Whether another version is compatible, following semver by comparing up to the first
+    /// non-zero component
+    pub fn compatible(&self, other: &str) -> bool {
+        fn significant(version: &str) -> Vec<&str> {
+            let parts = version.split('.').collect::<Vec<_>>();
+            let end = parts.iter().position(|p| *p != "0").unwrap_or(parts.len());
+            parts[..(end + 1).min(parts.len())].to_vec()
+        }
+
+        significant(&self.version) == significant(other)
+    }
+}